@@ -0,0 +1,133 @@
+use seymour_life::{simulator::{Simulator, SimulatorConfig, SimState, FaultScript, nth_serial},
+                   transport::{SerialTransport, Transport}};
+use std::{thread, time::Duration};
+use clap::{Parser, ValueEnum};
+
+#[derive(Clone,Copy,Debug,ValueEnum)]
+enum StartState{
+    Login,
+    Shell,
+    Menu,
+    Uboot,
+}
+
+#[derive(Parser,Debug)]
+#[command(author,version,about="Simulates Seymour units on pseudo-terminals")]
+struct Args{
+    /// Serial number of the first simulated unit. Further units count up from it.
+    #[arg(short,long,default_value="SIM00001")]
+    serial:String,
+
+    /// Number of units to simulate, each on its own pseudo-terminal
+    #[arg(short,long,default_value_t=1)]
+    count:u32,
+
+    /// State the units start in
+    #[arg(long,value_enum,default_value_t=StartState::Login)]
+    start:StartState,
+
+    /// Seconds from `shutdown -r now` to the login prompt
+    #[arg(long,default_value_t=5.0)]
+    boot_time:f64,
+
+    /// Seconds an NIBP reading stays in progress
+    #[arg(long,default_value_t=10.0)]
+    bp_duration:f64,
+
+    /// Probability that the prompt following a command is never sent
+    #[arg(long,default_value_t=0.0)]
+    drop_prompt_rate:f64,
+
+    /// Probability that garbage bytes precede the response to a command
+    #[arg(long,default_value_t=0.0)]
+    garbage_rate:f64,
+
+    /// Probability that a unit stalls before responding to a command
+    #[arg(long,default_value_t=0.0)]
+    stall_rate:f64,
+
+    /// Seconds a stall lasts
+    #[arg(long,default_value_t=10.0)]
+    stall_duration:f64,
+
+    /// Probability that a reboot stops at the U-Boot prompt
+    #[arg(long,default_value_t=0.0)]
+    uboot_rate:f64,
+
+    /// Seed for the fault generator
+    #[arg(long)]
+    seed:Option<u64>,
+
+    /// Fault script, applied to every simulated unit
+    #[arg(long)]
+    script:Option<String>,
+
+    /// Print debug logs
+    #[arg(short,long,action)]
+    debug:bool,
+}
+
+fn main(){
+    let args = Args::parse();
+    _ = fern::Dispatch::new()
+        .format(|out,message,record|{
+            out.finish(format_args!("{} - [{}] - {}",chrono::Local::now().to_rfc3339(),record.level(),message))
+        })
+        .level(if args.debug { log::LevelFilter::Debug } else { log::LevelFilter::Info })
+        .chain(std::io::stdout())
+        .apply();
+
+    let script = match args.script{
+        Some(ref path) => match FaultScript::load(path){
+            Ok(script) => script,
+            Err(error) => {
+                log::error!("{}",error);
+                std::process::exit(1);
+            }
+        },
+        None => FaultScript::default(),
+    };
+    let mut base_config = SimulatorConfig{
+        initial_state: match args.start{
+            StartState::Login => SimState::LoginPrompt,
+            StartState::Shell => SimState::ShellPrompt,
+            StartState::Menu => SimState::DebugMenu,
+            StartState::Uboot => SimState::UBoot,
+        },
+        boot_time: Duration::from_secs_f64(args.boot_time),
+        bp_duration: Duration::from_secs_f64(args.bp_duration),
+        drop_prompt_rate: args.drop_prompt_rate,
+        garbage_rate: args.garbage_rate,
+        stall_rate: args.stall_rate,
+        stall_duration: Duration::from_secs_f64(args.stall_duration),
+        uboot_rate: args.uboot_rate,
+        script,
+        ..Default::default()
+    };
+    if let Some(seed) = args.seed{
+        base_config.seed = seed;
+    }
+
+    let mut unit_threads = Vec::new();
+    //Slave ends are kept open so the master side doesn't see EIO between connections
+    let mut slaves = Vec::new();
+    for index in 0..args.count{
+        let (master, slave) = match SerialTransport::pty(){
+            Ok(pair) => pair,
+            Err(error) => {
+                log::error!("Unable to open pseudo-terminal: {}",error);
+                std::process::exit(1);
+            }
+        };
+        let mut config = base_config.clone();
+        config.serial = nth_serial(&args.serial,index);
+        config.seed = base_config.seed.wrapping_add(index as u64);
+        log::info!("Simulated unit {} listening on {}",config.serial,slave.name().unwrap_or("unknown".to_string()));
+        slaves.push(slave);
+        let simulator = Simulator::new(Box::new(master),config);
+        unit_threads.push(thread::spawn(move || simulator.run()));
+    }
+    for unit in unit_threads{
+        _ = unit.join();
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::BTreeMap, fs, time::Duration};
+use serde::Deserialize;
+use crate::{error::Error, simulator::{FaultScript, SimulatorConfig}};
+
+pub const DEFAULT_LOG_FOLDER: &str = "logs";
+
+//Everything needed to run without anyone at the keyboard, read from a TOML file
+#[derive(Clone,Default,PartialEq,Debug,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config{
+    pub iterations: Option<u64>,
+    pub target_reboots: Option<u64>,
+    pub target_bps: Option<u64>,
+    pub pins: Option<Vec<u8>>,
+    pub reserved_pins: Option<Vec<u8>>,
+    //Serial numbers for units that can't report their own, keyed by USB port path
+    pub serials: BTreeMap<String,String>,
+    //Path to a profiles file, as given with --profiles
+    pub profiles: Option<String>,
+    pub shell_on_exit: bool,
+    pub stall_timeout: Option<u64>,
+    pub recovery_timeout: Option<u64>,
+    pub logging: LoggingConfig,
+    //Faults for the units started by --simulate
+    pub simulate: SimulateConfig,
+}
+
+#[derive(Clone,PartialEq,Debug,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig{
+    pub debug: bool,
+    pub folder: String,
+    pub stdout: bool,
+}
+
+impl Default for LoggingConfig{
+    fn default() -> Self{
+        Self{ debug: false, folder: DEFAULT_LOG_FOLDER.to_string(), stdout: true }
+    }
+}
+
+#[derive(Clone,PartialEq,Debug,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulateConfig{
+    pub boot_seconds: f64,
+    pub bp_seconds: f64,
+    pub drop_prompt_rate: f64,
+    pub garbage_rate: f64,
+    pub stall_rate: f64,
+    pub stall_seconds: f64,
+    pub uboot_rate: f64,
+    pub seed: Option<u64>,
+    //Fault script applied to every simulated unit
+    pub script: Option<String>,
+}
+
+impl Default for SimulateConfig{
+    fn default() -> Self{
+        let simulator = SimulatorConfig::default();
+        Self{
+            boot_seconds: simulator.boot_time.as_secs_f64(),
+            bp_seconds: simulator.bp_duration.as_secs_f64(),
+            drop_prompt_rate: simulator.drop_prompt_rate,
+            garbage_rate: simulator.garbage_rate,
+            stall_rate: simulator.stall_rate,
+            stall_seconds: simulator.stall_duration.as_secs_f64(),
+            uboot_rate: simulator.uboot_rate,
+            seed: None,
+            script: None,
+        }
+    }
+}
+
+impl SimulateConfig{
+    fn validate(&self, path:&str) -> Result<(),Error>{
+        let rates = [("drop_prompt_rate",self.drop_prompt_rate),("garbage_rate",self.garbage_rate),("stall_rate",self.stall_rate),("uboot_rate",self.uboot_rate)];
+        for (name,rate) in rates{
+            if !(0.0..=1.0).contains(&rate){
+                return Err(Error::Config(format!("{}: simulate.{} must be between 0 and 1",path,name)));
+            }
+        }
+        let durations = [("boot_seconds",self.boot_seconds),("bp_seconds",self.bp_seconds),("stall_seconds",self.stall_seconds)];
+        for (name,seconds) in durations{
+            if !seconds.is_finite() || seconds < 0.0{
+                return Err(Error::Config(format!("{}: simulate.{} must be zero or more",path,name)));
+            }
+        }
+        return Ok(());
+    }
+
+    //Settings shared by every simulated unit. Each unit is given its own serial and seed from these.
+    pub fn simulator_config(&self) -> Result<SimulatorConfig,Error>{
+        let script = match self.script{
+            Some(ref path) => FaultScript::load(path).map_err(Error::Config)?,
+            None => FaultScript::default(),
+        };
+        let mut config = SimulatorConfig{
+            boot_time: Duration::from_secs_f64(self.boot_seconds),
+            bp_duration: Duration::from_secs_f64(self.bp_seconds),
+            drop_prompt_rate: self.drop_prompt_rate,
+            garbage_rate: self.garbage_rate,
+            stall_rate: self.stall_rate,
+            stall_duration: Duration::from_secs_f64(self.stall_seconds),
+            uboot_rate: self.uboot_rate,
+            script,
+            ..Default::default()
+        };
+        if let Some(seed) = self.seed{
+            config.seed = seed;
+        }
+        return Ok(config);
+    }
+}
+
+impl Config{
+    pub fn load(path:&str) -> Result<Self,Error>{
+        let contents = fs::read_to_string(path).map_err(|source| Error::Persistence{ path: path.to_string(), source })?;
+        let config = toml::from_str::<Self>(&contents).map_err(|error| Error::Config(format!("{}: {}",path,error)))?;
+        if config.iterations == Some(0){
+            return Err(Error::Config(format!("{}: iterations must be at least 1",path)));
+        }
+        config.simulate.validate(path)?;
+        for (port,serial) in config.serials.iter(){
+            if serial.trim().is_empty(){
+                return Err(Error::Config(format!("{}: serial for {} is empty",path,port)));
+            }
+        }
+        return Ok(config);
+    }
+
+    //A port may be listed under any of its names (a by-path link, or the ttyUSB device it
+    //points to), so both sides are resolved before giving up
+    pub fn serial_for(&self, port:&str) -> Option<&str>{
+        if let Some(serial) = self.serials.get(port){
+            return Some(serial);
+        }
+        let resolved_port = fs::canonicalize(port).ok()?;
+        return self.serials.iter()
+            .find(|(path,_)| fs::canonicalize(path).is_ok_and(|path| path == resolved_port))
+            .map(|(_,serial)| serial.as_str());
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn test_folder(name:&str) -> std::path::PathBuf{
+        let folder = std::env::temp_dir().join(format!("seymour_config_{}_{}",name,std::process::id()));
+        _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        return folder;
+    }
+
+    fn load(folder:&std::path::Path, contents:&str) -> Result<Config,Error>{
+        let path = folder.join("config.toml");
+        fs::write(&path,contents).unwrap();
+        return Config::load(&path.to_string_lossy());
+    }
+
+    #[test]
+    fn unusable_settings_are_rejected(){
+        let folder = test_folder("rejected");
+        let zero_iterations = load(&folder,"iterations = 0\n");
+        let empty_serial = load(&folder,"[serials]\n\"/dev/ttyUSB0\" = \"  \"\n");
+        let bad_rate = load(&folder,"[simulate]\ngarbage_rate = 1.5\n");
+        let valid = load(&folder,"iterations = 1\n\n[serials]\n\"/dev/ttyUSB0\" = \"A\"\n");
+        _ = fs::remove_dir_all(&folder);
+        assert!(matches!(zero_iterations,Err(Error::Config(message)) if message.contains("iterations")));
+        assert!(matches!(empty_serial,Err(Error::Config(message)) if message.contains("serial")));
+        assert!(matches!(bad_rate,Err(Error::Config(message)) if message.contains("garbage_rate")));
+        assert_eq!(valid.unwrap().iterations,Some(1));
+    }
+
+    #[test]
+    fn serials_are_found_under_any_name_for_a_port(){
+        let folder = test_folder("serials");
+        let device = folder.join("ttyUSB0");
+        let link = folder.join("usb-port-1");
+        fs::write(&device,"").unwrap();
+        std::os::unix::fs::symlink(&device,&link).unwrap();
+        let mut config = Config::default();
+        config.serials.insert("/dev/ttyUSB9".to_string(),"A".to_string());
+        config.serials.insert(device.to_string_lossy().to_string(),"B".to_string());
+        let direct = config.serial_for("/dev/ttyUSB9").map(str::to_string);
+        let linked = config.serial_for(&link.to_string_lossy()).map(str::to_string);
+        let unknown = config.serial_for(&folder.join("ttyUSB1").to_string_lossy()).map(str::to_string);
+        _ = fs::remove_dir_all(&folder);
+        assert_eq!(direct.as_deref(),Some("A"));
+        assert_eq!(linked.as_deref(),Some("B"));
+        assert_eq!(unknown,None);
+    }
+}
@@ -1,16 +1,51 @@
-use std::{fs::{self, File}, path::Path, io::Write, thread, time::Duration};
+use std::{sync::Arc, thread, time::{Duration, Instant}};
+use chrono::{DateTime, Local};
 use crate::tty::{TTY, Response,Command};
-use rppal::gpio::{Gpio,OutputPin};
-
-const TEMP_WAIT:Duration = Duration::from_secs(3);
-const REBOOTS_SECTION: &str = "Reboots";
-const BP_SECTION: &str = "Successful BP tests";
-const TEMP_SECTION: &str = "Successful temp tests";
-const OUTPUT_FOLDER: &str = "output/";
-const SECTION_SEPARATOR: &str = ": ";
-const UNINITIALISED_SERIAL: &str = "uninitialised";
+use crate::error::Error;
+use crate::gpio_facade::{GpioBackend,RelayOutput};
+use crate::results::{DeviceResults,RigInfo,Targets,Anomalies,BpStats,PullStats};
+use crate::journal::{Journal,Event,BpOutcome};
+use crate::shutdown;
+use crate::profile::Profile;
+use crate::supervisor::{Heartbeat,UnitStatus};
+use crate::timing::{BootClock,BootPhase,BootTiming,BootTimes,TimingStats};
+
+const BOOT_TIMEOUT:Duration = Duration::from_secs(180);
+const LOGIN_TIMEOUT:Duration = Duration::from_secs(30);
+const MENU_TIMEOUT:Duration = Duration::from_secs(30);
+const SERIAL_TIMEOUT:Duration = Duration::from_secs(30);
+const BP_CHECK_TIMEOUT:Duration = Duration::from_secs(5);
+//Boots a unit gets from U-Boot before it is given up on, in case it keeps landing back there
+const UBOOT_BOOT_ATTEMPTS:u32 = 3;
+//Times a brightness change is tried before giving up, in case the unit keeps landing in U-Boot
+const BRIGHTNESS_ATTEMPTS:u32 = 3;
+//Pulls in a row that can go unregistered before the relay or probe well is flagged as faulty
+const MISSED_PULLS_FOR_FAULT:u64 = 3;
+pub const OUTPUT_FOLDER: &str = "output/";
+pub const UNINITIALISED_SERIAL: &str = "uninitialised";
 const SERIAL_HEADER: &str = "DtCtrlCfgDeviceSerialNum";
-#[derive(PartialEq,Debug)]
+//Ways of bringing back a stalled unit, lightest first
+const RECOVERY_STEPS: [RecoveryStep;4] = [RecoveryStep::Newline,RecoveryStep::QuitMenu,RecoveryStep::Reboot,RecoveryStep::UBootBoot];
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+enum RecoveryStep{
+    Newline,
+    QuitMenu,
+    Reboot,
+    UBootBoot,
+}
+
+impl RecoveryStep{
+    fn name(&self) -> &'static str{
+        match self{
+            RecoveryStep::Newline => "newline",
+            RecoveryStep::QuitMenu => "quit menu",
+            RecoveryStep::Reboot => "reboot",
+            RecoveryStep::UBootBoot => "U-Boot boot",
+        }
+    }
+}
+#[derive(Clone,Copy,PartialEq,Debug)]
 pub enum State{
     Shutdown,
     LoginPrompt,
@@ -20,103 +55,147 @@ pub enum State{
     ShellPrompt
 }
 
+impl State{
+    //Where a unit in this state must be after sending the given response, if the response
+    //says anything about it. Menus all share a prompt, so a menu prompt keeps the current menu.
+    pub fn after_response(&self, response:&Response) -> Option<State>{
+        match response{
+            Response::LoginPrompt | Response::PasswordPrompt => Some(State::LoginPrompt),
+            Response::ShellPrompt | Response::PreShellPrompt | Response::FailedDebugMenu | 
+            Response::ShuttingDown => Some(State::ShellPrompt),
+            Response::Rebooting | Response::UBoot => Some(State::Shutdown),
+            Response::DebugMenu | Response::DebugInit | Response::BPOn | Response::BPOff | Response::TempCount(_) => {
+                match self{
+                    State::DebugMenu | State::LifecycleMenu | State::BrightnessMenu => Some(*self),
+                    _ => Some(State::DebugMenu),
+                }
+            },
+            Response::Serial(_) | Response::Other | Response::Empty | Response::EmptyNewline => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Device{
     usb_tty:TTY,
-    output_file: Option<File>,
-    gpio: rppal::gpio::Gpio,
+    first_run: Option<DateTime<Local>>,
+    journal: Option<Journal>,
+    gpio: Arc<dyn GpioBackend>,
     address: Option<u8>,
-    pin: Option<OutputPin>,
+    pin: Option<Box<dyn RelayOutput>>,
     serial: String,
     current_state: State,
     reboots: u64,
     temps: u64,
     init_temps: u64,
     temp_offset: u64,
-    bps: u64
+    bps: u64,
+    target: Targets,
+    profile: Profile,
+    heartbeat: Option<Heartbeat>,
+    anomalies: Anomalies,
+    pull_stats: PullStats,
+    //Running from the shutdown command until the debug menu loads again
+    boot_clock: Option<BootClock>,
+    boot_timings: Vec<BootTiming>,
+    boot_times: BootTimes,
+    //Durations of completed BPs, in milliseconds
+    bp_durations: Vec<u64>,
+    bp_stats: BpStats
 }
 
 impl Device{
-    fn load_values(&mut self) -> bool {
-        if ! Path::new(&OUTPUT_FOLDER).is_dir(){
-            _ = fs::create_dir(&OUTPUT_FOLDER);
-        };
-        //log::debug!("{:?}",&self.serial);
-        let output_path:String = OUTPUT_FOLDER.to_owned() + &self.serial + ".txt";
-        if ! Path::new(&output_path).exists(){
-            log::debug!("Creating file {}",&output_path);
-            let temp:Result<File, std::io::Error> = fs::File::create(&output_path);
-            match temp{
-                Ok(file) => {
-                    self.output_file = Some(file);
-                    self.save_values();
-                }
-                Err(_) => {
-                    return false
-                }
+    fn load_values(&mut self) -> Result<(),Error> {
+        if !self.has_serial() { return Ok(()); }
+        match DeviceResults::load(OUTPUT_FOLDER,&self.serial)?{
+            Some(results) => {
+                log::trace!("{:?}",results);
+                self.reboots = results.reboots;
+                self.bps = results.bps;
+                self.temp_offset = results.temps;
+                self.first_run = Some(results.first_run);
+                self.target = results.target;
+                self.anomalies = results.anomalies;
+                self.pull_stats = results.pull_stats;
+                self.open_journal();
+                self.load_history();
+                self.check_journal(&results);
+            },
+            None => {
+                //Anything counted so far belonged to whatever serial this was loaded under before
+                log::debug!("Creating results for {}",&self.serial);
+                self.reboots = 0;
+                self.bps = 0;
+                self.temp_offset = 0;
+                self.first_run = None;
+                self.target = Targets::default();
+                self.anomalies = Anomalies::default();
+                self.pull_stats = PullStats::default();
+                self.clear_history();
+                self.open_journal();
+                self.save_values()?;
             }
         }
-        else {
-            let temp:Result<String, std::io::Error> = std::fs::read_to_string(output_path);
-            match temp{
-                Ok(file_contents) =>{
-                    let file_lines:Vec<&str> = file_contents.split("\n").collect();
-                    log::trace!("{:?}",file_contents);
-                    for line in file_lines {
-                        if line.len() > 0{
-                            //log::trace!("{:?}",line);
-                            let section_and_data:Vec<&str> = line.split(SECTION_SEPARATOR).collect();
-                            let section:&str = section_and_data[0];
-                            let possible_value:Result<u64, std::num::ParseIntError> = section_and_data[1].trim().parse::<u64>();
-                            match possible_value{
-                                Ok(value) => {
-                                    //log::trace!("{:?} value: [{:?}]",section,value);
-                                    match section {
-                                        REBOOTS_SECTION => {
-                                            self.reboots = value;
-                                            //log::trace!("Reboots set to {:?}",self.reboots);
-                                        },
-                                        BP_SECTION => {
-                                            self.bps = value.clone();
-                                            //log::trace!("BPS set to {:?}",self.bps);
-                                        },
-                                        TEMP_SECTION => {
-                                            self.temp_offset = value;
-                                            //log::trace!("Temp offset set to {:?}",self.temp_offset);
-                                        },
-                                        _ => {
-                                            log::warn!("Invalid import value: [{:?}]. Please ensure that the output directory is clean.",section_and_data);
-                                        }
-                                    };
-                                }
-                                Err(_) => {
-                                    log::warn!("Unable to parse value [{:?}] into integer",section_and_data);
-                                }
-                            }
-                        };
-                    };
-                },
-                Err(error) => {
-                    log::warn!("Could not load from file!");
-                    log::debug!("{}",error);
-                }
+        return Ok(())
+    }
+
+    //A journal that doesn't add up to the saved totals is only warned about, as the totals are
+    //what testing carries on from
+    fn check_journal(&self, results:&DeviceResults){
+        match Journal::totals(OUTPUT_FOLDER,&self.serial){
+            Ok(totals) if (totals.reboots,totals.bps,totals.temps) != (results.reboots,results.bps,results.temps) => {
+                log::warn!("Journal for device {} adds up to {:?}, but its results have {} reboots, {} BPs and {} temps",
+                           self.serial,totals,results.reboots,results.bps,results.temps);
+            },
+            Ok(_) => {},
+            Err(error) => log::warn!("Unable to check journal for device {}: {}",self.serial,error),
+        }
+    }
+
+    //Opens this serial's journal. A unit with totals but no journal yet gets them recorded as
+    //a baseline, so the journal always adds up to the totals.
+    fn open_journal(&mut self){
+        self.journal = match Journal::open(OUTPUT_FOLDER,&self.serial){
+            Ok(journal) => Some(journal),
+            Err(error) => {
+                log::warn!("Unable to open journal for {}: {}",self.serial,error);
+                None
             }
         };
-        return true
+        let baseline = Event::Baseline{ reboots: self.reboots, bps: self.bps, temps: self.temp_offset };
+        if let Some(ref mut journal) = self.journal{
+            if journal.is_new() && baseline != (Event::Baseline{ reboots: 0, bps: 0, temps: 0 }){
+                journal.record(baseline,None);
+            }
+        }
     }
-    pub fn new(mut usb_port:TTY,response:Option<Response>) -> Result<Self,String>{
+
+    fn record(&mut self, event:Event, duration:Option<Duration>){
+        if event.is_progress(){
+            if let Some(ref heartbeat) = self.heartbeat{
+                heartbeat.beat();
+            }
+        }
+        if let Some(ref mut journal) = self.journal{
+            journal.record(event,duration);
+        }
+    }
+    pub fn new(mut usb_port:TTY,response:Option<Response>,gpio:Arc<dyn GpioBackend>) -> Result<Self,Error>{
         let initial_state:State;
         match response{
             Some(response_value)=> {
                 match response_value{
                     Response::PasswordPrompt=>{
-                        usb_port.write_to_device(Command::Newline);
-                        _ = usb_port.read_from_device(None);
+                        usb_port.write_to_device(Command::Newline)?;
+                        usb_port.read_from_device(None)?;
                         initial_state = State::LoginPrompt;
                     },
                     Response::UBoot=>{
-                        usb_port.write_to_device(Command::Boot);
-                        while usb_port.read_from_device(None) != Response::LoginPrompt {}
+                        usb_port.write_to_device(Command::Boot)?;
+                        if let Err(error) = usb_port.expect(&[Response::LoginPrompt],BOOT_TIMEOUT){
+                            log::error!("TTY {:?} did not boot from U-Boot: {}",usb_port,error);
+                            return Err(error);
+                        }
                         initial_state = State::LoginPrompt;
                     },
                         //Response::Empty parsing here is potentially in bad faith
@@ -125,34 +204,37 @@ impl Device{
                         initial_state = State::LoginPrompt,
                     Response::BPOn | Response::BPOff | Response::TempCount(_) |
                     Response::DebugMenu=>{
-                        usb_port.write_to_device(Command::Quit);
-                        match usb_port.read_from_device(None){
-                            Response::ShuttingDown | Response::Rebooting => {
-                                while usb_port.read_from_device(None) != Response::LoginPrompt {}
+                        usb_port.write_to_device(Command::Quit)?;
+                        match usb_port.read_from_device(None)?{
+                            Response::Rebooting => {
+                                if let Err(error) = usb_port.expect(&[Response::LoginPrompt],BOOT_TIMEOUT){
+                                    log::error!("TTY {:?} did not finish rebooting: {}",usb_port,error);
+                                    return Err(error);
+                                }
                                 initial_state = State::LoginPrompt;
                             },
-                            Response::ShellPrompt => {
+                            Response::ShuttingDown | Response::ShellPrompt => {
                                 initial_state = State::ShellPrompt;
                             },
                             Response::DebugMenu => {
-                                usb_port.write_to_device(Command::Newline);
-                                match usb_port.read_from_device(None) {
+                                usb_port.write_to_device(Command::Newline)?;
+                                match usb_port.read_from_device(None)? {
                                     Response::DebugMenu | Response::ShellPrompt => {
                                         initial_state = State::ShellPrompt;
                                     },
-                                    _ => {
+                                    unexpected => {
                                         log::error!("Unknown state for TTY {:?}!!! Consult logs immediately.",usb_port);
                                         log::debug!("Last known state: DebugMenu.");
                                         log::debug!("Assumed but incorrect current state: successfully exited debug menu");
-                                        return Err("Failed TTY init. Unknown state, cannot trust.".to_string());
+                                        return Err(Error::UnexpectedResponse(unexpected));
                                     }
                                 };
                             },
-                            _ => {
+                            unexpected => {
                                 log::error!("Unknown state for TTY {:?}!!! Consult logs immediately.",usb_port);
                                 log::debug!("Last known state: DebugMenu.");
                                 log::debug!("Assumed but incorrect current state: attempted to exit debug menu");
-                                return Err("Failed TTY init. Unknown state, cannot trust.".to_string());
+                                return Err(Error::UnexpectedResponse(unexpected));
                             }
                         };
                     },
@@ -160,291 +242,407 @@ impl Device{
                         Response::Serial(_) | Response::EmptyNewline => {
                             log::error!("Unknown state for TTY {:?}!!! Consult logs immediately.",usb_port);
                             log::debug!("How did I get here???");
-                            return Err("Failed TTY init. Unknown state, cannot trust.".to_string());
+                            return Err(Error::UnexpectedResponse(response_value));
                     },
                 };
             },
             None => initial_state = State::LoginPrompt
         };
-        let temp = Gpio::new();
-        match temp{
-            Ok(gpio) =>{
-                let mut output = Self{
-                    usb_tty: usb_port,
-                    gpio,
-                    address: None,
-                    pin: None,
-                    output_file: None,
-                    serial: UNINITIALISED_SERIAL.to_string(),
-                    current_state: initial_state,
-                    reboots: 0,
-                    temps: 0,
-                    temp_offset: 0,
-                    init_temps: 0,
-                    bps: 0
-                };
-                if !output.load_values(){
-                    log::warn!("Could not load values from file! File may be overwritten.");
-                }
-                return Ok(output);
-            }
-            Err(error) => {
-                log::warn!("Failed to init GPIO!");
-                log::debug!("{}",error);
-                return Err("Failed GPIO init".to_string());
-            }
+        let output = Self{
+            usb_tty: usb_port,
+            gpio,
+            address: None,
+            pin: None,
+            first_run: None,
+            journal: None,
+            serial: UNINITIALISED_SERIAL.to_string(),
+            current_state: initial_state,
+            reboots: 0,
+            temps: 0,
+            temp_offset: 0,
+            init_temps: 0,
+            bps: 0,
+            target: Targets::default(),
+            profile: Profile::default(),
+            heartbeat: None,
+            anomalies: Anomalies::default(),
+            pull_stats: PullStats::default(),
+            boot_clock: None,
+            boot_timings: Vec::new(),
+            boot_times: BootTimes::default(),
+            bp_durations: Vec::new(),
+            bp_stats: BpStats::default()
+        };
+        //Results are loaded once the serial is known
+        return Ok(output);
+    }
+
+    fn go_to_brightness_menu(&mut self) -> Result<&mut Self,Error>{
+        if let Err(error) = self.enter_brightness_menu(){
+            self.record(Event::NavigationFailed{ menu: "brightness".to_string(), error: error.to_string() },None);
+            return Err(error);
         }
+        return Ok(self);
     }
 
-    fn go_to_brightness_menu(&mut self) -> &mut Self{
+    fn go_to_lifecycle_menu(&mut self) -> Result<&mut Self,Error>{
+        if let Err(error) = self.enter_lifecycle_menu(){
+            self.record(Event::NavigationFailed{ menu: "lifecycle".to_string(), error: error.to_string() },None);
+            return Err(error);
+        }
+        return Ok(self);
+    }
+
+    fn enter_brightness_menu(&mut self) -> Result<(),Error>{
         while !(self.current_state == State::BrightnessMenu){
             match self.current_state {
-                State::BrightnessMenu => return self,
+                State::BrightnessMenu => return Ok(()),
                 State::DebugMenu => {
-                    self.usb_tty.write_to_device(Command::LifecycleMenu);
-                    _ = self.usb_tty.read_from_device(None);
+                    self.usb_tty.write_to_device(Command::LifecycleMenu)?;
+                    if self.read()? == Response::UBoot { continue; }
                     self.current_state = State::LifecycleMenu;
                 },
                 State::LifecycleMenu =>{
-                    self.usb_tty.write_to_device(Command::BrightnessMenu);
-                    _ = self.usb_tty.read_from_device(None);
+                    self.usb_tty.write_to_device(Command::BrightnessMenu)?;
+                    if self.read()? == Response::UBoot { continue; }
                     self.current_state = State::BrightnessMenu;
-                    return self;
-                },
-                State::LoginPrompt => {
-                    self.usb_tty.write_to_device(Command::Login);
-                    loop {
-                        match self.usb_tty.read_from_device(None){
-                            Response::PreShellPrompt | Response::Empty | Response::ShuttingDown | 
-                            Response::DebugInit | Response::EmptyNewline | Response::Rebooting => {},
-                            Response::PasswordPrompt => {self.usb_tty.write_to_device(Command::Newline);},
-                            Response::FailedDebugMenu | Response::ShellPrompt => break,
-                            _ => {
-                                log::error!("Unexpected response from device {}!",self.serial);
-                                log::debug!("brightness menu, catch-all, login loop, {}, {:?}",self.serial,self.usb_tty);
-                                log::error!("Unsure how to continue. Expect data from device {} to be erratic until next cycle.",self.serial);
-                                //break;
-                            },
-                        };
-                    };
-                    self.current_state = State::ShellPrompt;
-                },
-                State::ShellPrompt => {
-                    self.usb_tty.write_to_device(Command::DebugMenu);
-                    loop {
-                        match self.usb_tty.read_from_device(None)   {
-                            Response::PreShellPrompt | Response::Empty | Response::ShuttingDown | 
-                            Response::DebugInit | Response::EmptyNewline | Response::Rebooting => {},
-                            Response::LoginPrompt => {
-                                self.usb_tty.write_to_device(Command::Login);
-                                while self.usb_tty.read_from_device(None) != Response::ShellPrompt {};
-                                self.usb_tty.write_to_device(Command::DebugMenu);
-                            },
-                            Response::DebugMenu =>
-                                break,
-                            Response::FailedDebugMenu => {
-                                self.usb_tty.write_to_device(Command::DebugMenu);
-                            },
-                            _ => { 
-                                log::error!("Unexpected response from device {}!", self.serial);
-                                log::debug!("brightness menu, catch-all, shell prompt loop, {}, {:?}",self.serial,self.usb_tty);
-                                log::error!("Unsure how to continue. Expect data from device {} to be erratic until next cycle.",self.serial);
-                                //break;
-                            },
-                        };
-                    };
-                    //_ = self.usb_tty.read_from_device(None);
-                    self.current_state = State::DebugMenu;
-                },
-                State::Shutdown => {
-                    while self.usb_tty.read_from_device(None) != Response::LoginPrompt{}
-                    self.current_state = State::LoginPrompt;
+                    return Ok(());
                 },
+                State::LoginPrompt => self.log_in()?,
+                State::ShellPrompt => self.open_debug_menu()?,
+                State::Shutdown => self.wait_for_login_prompt()?,
             };
         };
-        return self;
+        return Ok(());
     }
 
-    fn go_to_lifecycle_menu(&mut self) -> &mut Self{
+    fn enter_lifecycle_menu(&mut self) -> Result<(),Error>{
         while !(self.current_state == State::LifecycleMenu){
             match self.current_state {
-                State::LifecycleMenu => return self,
+                State::LifecycleMenu => return Ok(()),
                 State::DebugMenu => {
-                    self.usb_tty.write_to_device(Command::LifecycleMenu);
-                    _ = self.usb_tty.read_from_device(None);
+                    self.usb_tty.write_to_device(Command::LifecycleMenu)?;
+                    if self.read()? == Response::UBoot { continue; }
                     self.current_state = State::LifecycleMenu;
-                    return self;
+                    return Ok(());
                 },
                 State::BrightnessMenu =>{
-                    self.usb_tty.write_to_device(Command::UpMenuLevel);
-                    _ = self.usb_tty.read_from_device(None);
+                    self.usb_tty.write_to_device(Command::UpMenuLevel)?;
+                    if self.read()? == Response::UBoot { continue; }
                     self.current_state = State::LifecycleMenu;
-                    return self;
+                    return Ok(());
                 },
-                State::LoginPrompt => {
-                    self.usb_tty.write_to_device(Command::Login);
-                    loop {
-                        match self.usb_tty.read_from_device(None){
-                            Response::PreShellPrompt | Response::Empty | Response::ShuttingDown | 
-                            Response::DebugInit | Response::EmptyNewline | Response::Rebooting => {},
-                            Response::PasswordPrompt => {self.usb_tty.write_to_device(Command::Newline);},
-                            Response::FailedDebugMenu | Response::ShellPrompt => break,
-                            _ => {
-                                log::error!("Unexpected response from device {}!",self.serial);
-                                log::debug!("lifecycle menu, catch-all, first loop, {}, {:?}",self.serial,self.usb_tty);
-                                log::error!("Unsure how to continue. Expect data from device {} to be erratic until next cycle.",self.serial);
-                                //break;
-                            },
-                        };
-                    };
-                    self.current_state = State::ShellPrompt;
-                },
-                State::ShellPrompt => {
-                    self.usb_tty.write_to_device(Command::DebugMenu);
-                    loop {
-                        let read_in = self.usb_tty.read_from_device(None);
-                        match read_in {
-                            Response::PreShellPrompt | Response::Empty | Response::ShuttingDown | 
-                            Response::DebugInit | Response::EmptyNewline | Response::Rebooting => {},
-                            Response::LoginPrompt => {
-                                self.usb_tty.write_to_device(Command::DebugMenu);
-                            },
-                            Response::DebugMenu =>
-                                break,
-                            Response::FailedDebugMenu => {
-                                self.usb_tty.write_to_device(Command::DebugMenu);
-                            },
-                            _ => { 
-                                log::error!("Unexpected response from device {}! {:?}", self.serial, read_in);
-                                log::debug!("lifecycle menu, catch-all, second loop, {}, {:?}",self.serial,self.usb_tty);
-                                log::error!("Unsure how to continue. Expect data from device {} to be erratic until next cycle.",self.serial);
-                                //break;
-                            },
-                        };
-                    };
-                    self.current_state = State::DebugMenu;
+                State::LoginPrompt => self.log_in()?,
+                State::ShellPrompt => self.open_debug_menu()?,
+                State::Shutdown => self.wait_for_login_prompt()?,
+            };
+        };
+        return Ok(());
+    }
+
+    fn log_in(&mut self) -> Result<(),Error>{
+        self.usb_tty.write_to_device(Command::Login)?;
+        match self.expect(&[Response::ShellPrompt,Response::FailedDebugMenu],LOGIN_TIMEOUT)?{
+            Response::UBoot => return Ok(()),
+            Response::ShellPrompt => self.mark_boot(BootPhase::ShellPrompt),
+            _ => {},
+        }
+        self.current_state = State::ShellPrompt;
+        return Ok(());
+    }
+
+    fn open_debug_menu(&mut self) -> Result<(),Error>{
+        let start = Instant::now();
+        self.usb_tty.write_to_device(Command::DebugMenu)?;
+        loop {
+            let remaining = MENU_TIMEOUT.saturating_sub(start.elapsed());
+            match self.expect(&[Response::DebugMenu,Response::DebugInit,Response::FailedDebugMenu,Response::LoginPrompt],remaining)? {
+                Response::LoginPrompt => {
+                    self.mark_boot(BootPhase::LoginPrompt);
+                    self.usb_tty.write_to_device(Command::Login)?;
+                    if self.expect(&[Response::ShellPrompt],LOGIN_TIMEOUT)? == Response::UBoot { return Ok(()); }
+                    self.mark_boot(BootPhase::ShellPrompt);
+                    self.usb_tty.write_to_device(Command::DebugMenu)?;
                 },
-                State::Shutdown => {
-                    while self.usb_tty.read_from_device(None) != Response::LoginPrompt {}
-                    self.current_state = State::LoginPrompt;
+                Response::DebugInit => self.mark_boot(BootPhase::AppFramework),
+                Response::FailedDebugMenu => {
+                    self.usb_tty.write_to_device(Command::DebugMenu)?;
                 },
+                Response::UBoot => return Ok(()),
+                _ => break,
             };
         };
-        return self;
+        self.current_state = State::DebugMenu;
+        self.finish_boot_timing(true);
+        return Ok(());
     }
 
-    fn save_values(&mut self) -> bool{
-        let output_path = OUTPUT_FOLDER.to_owned() + &self.serial + ".txt";
-        let temp = fs::OpenOptions::new().write(true).truncate(true).open(&output_path);
-        match temp{
-            Ok(opened_file) => self.output_file = Some(opened_file),
-            Err(_) => {
-                log::warn!("Could not open file [{}] to write! Potential permissions error.",&output_path);
-                return false
-            }
+    fn mark_boot(&mut self, phase:BootPhase){
+        if let Some(ref mut clock) = self.boot_clock{
+            clock.mark(phase);
         }
-        if let Some(ref mut file_name) = self.output_file{
-            let mut output_data = REBOOTS_SECTION.to_string();
-            output_data.push_str(SECTION_SEPARATOR);
-            output_data.push_str(&self.reboots.to_string());
-            output_data.push_str("\n");
-            output_data.push_str(BP_SECTION);
-            output_data.push_str(SECTION_SEPARATOR);
-            output_data.push_str(&self.bps.to_string());
-            output_data.push_str("\n");
-            output_data.push_str(TEMP_SECTION);
-            output_data.push_str(SECTION_SEPARATOR);
-            let saved_temps = (self.temps - self.init_temps) + self.temp_offset;
-            output_data.push_str(&saved_temps.to_string());
-            output_data.push_str("\n");
-            log::debug!("final data to write to '{:?}': [{:?}]",file_name,output_data);
-            let temp = file_name.write_all(output_data.as_bytes());
-            match temp{
-                Err(error) => {
-                    log::warn!("{}",error);
+    }
+
+    //Called once the debug menu is up, or as incomplete when the unit won't get there. Timings
+    //missing the app framework are kept, as the earlier phases are still worth having.
+    fn finish_boot_timing(&mut self, complete:bool){
+        let Some(clock) = self.boot_clock.take() else { return; };
+        let mut timing = clock.timing();
+        timing.incomplete = !complete;
+        log::debug!("Boot timing for device {}: {:?}",self.serial,timing);
+        self.boot_timings.push(timing);
+        self.boot_times = BootTimes::from_timings(&self.boot_timings);
+        self.record(Event::BootTimed(timing),None);
+    }
+
+    fn clear_history(&mut self){
+        self.boot_timings.clear();
+        self.boot_times = BootTimes::default();
+        self.bp_durations.clear();
+        self.bp_stats = BpStats::default();
+    }
+
+    //Boot timings and BP outcomes are only summarised in the results, so the journal is read
+    //back for what the summaries were built from
+    fn load_history(&mut self){
+        self.clear_history();
+        let records = match Journal::read(OUTPUT_FOLDER,&self.serial){
+            Ok(records) => records,
+            Err(error) => {
+                log::warn!("Unable to read history for device {}: {}",self.serial,error);
+                return;
+            }
+        };
+        for record in records{
+            match record.event{
+                Event::BootTimed(timing) => self.boot_timings.push(timing),
+                Event::BpFinished{ outcome: Some(outcome), .. } => {
+                    self.bp_stats.add(outcome);
+                    if outcome == BpOutcome::Completed{
+                        self.bp_durations.extend(record.duration_ms);
+                    }
                 },
-                _ => {}
+                _ => {},
             }
         }
-        else {
-            log::warn!("Cannot write to output file!");
+        self.boot_times = BootTimes::from_timings(&self.boot_timings);
+        self.bp_stats.duration = TimingStats::from_samples(&self.bp_durations);
+    }
+
+    fn wait_for_login_prompt(&mut self) -> Result<(),Error>{
+        self.expect(&[Response::LoginPrompt],BOOT_TIMEOUT)?;
+        self.current_state = State::LoginPrompt;
+        return Ok(());
+    }
+
+    //Reads the next response. A unit found at a U-Boot prompt is booted and left at the login
+    //prompt, and Response::UBoot is returned so the caller knows to pick up from there.
+    fn read(&mut self) -> Result<Response,Error>{
+        let response = self.usb_tty.read_from_device(None)?;
+        if response == Response::UBoot{
+            self.boot_from_uboot()?;
         }
-        return true
+        return Ok(response);
     }
-    pub fn auto_set_serial(&mut self) -> bool{
+
+    //As TTY::expect, but a U-Boot prompt is also accepted and dealt with as in `read`
+    fn expect(&mut self, expected:&[Response], timeout:Duration) -> Result<Response,Error>{
+        let mut candidates = expected.to_vec();
+        candidates.push(Response::UBoot);
+        let response = self.usb_tty.expect(&candidates,timeout)?;
+        //Every wait during recovery has its own deadline, so an answer is enough to show the
+        //unit is still being worked on
+        if self.status() == UnitStatus::Recovering{
+            if let Some(ref heartbeat) = self.heartbeat{
+                heartbeat.beat();
+            }
+        }
+        if response == Response::UBoot{
+            self.boot_from_uboot()?;
+        }
+        return Ok(response);
+    }
+
+    fn boot_from_uboot(&mut self) -> Result<(),Error>{
+        let start = Instant::now();
+        //Time spent sat in U-Boot would throw out the boot timings
+        self.boot_clock = None;
+        for attempt in 1..=UBOOT_BOOT_ATTEMPTS{
+            log::warn!("Device {} is sitting at a U-Boot prompt, booting it (attempt {} of {})",self.serial,attempt,UBOOT_BOOT_ATTEMPTS);
+            self.anomalies.uboot_prompts += 1;
+            self.record(Event::UBootBooted,None);
+            if let Err(error) = self.save_values(){
+                log::warn!("Unable to save U-Boot count for device {}: {}",self.serial,error);
+            }
+            self.usb_tty.write_to_device(Command::Boot)?;
+            self.current_state = State::Shutdown;
+            if self.usb_tty.expect(&[Response::LoginPrompt,Response::UBoot],BOOT_TIMEOUT)? == Response::LoginPrompt{
+                self.current_state = State::LoginPrompt;
+                return Ok(());
+            }
+        }
+        return Err(Error::Timeout{ expected: vec![Response::LoginPrompt], waited: start.elapsed(), last: Some(Response::UBoot) });
+    }
+
+    //Nothing is saved until the unit's serial is known, as it would only be saved under a
+    //placeholder shared by every unit
+    fn save_values(&mut self) -> Result<(),Error>{
+        if !self.has_serial() { return Ok(()); }
+        let mut results = DeviceResults::new(&self.serial);
+        results.reboots = self.reboots;
+        results.bps = self.bps;
+        results.temps = self.temps.saturating_sub(self.init_temps) + self.temp_offset;
+        results.first_run = *self.first_run.get_or_insert(results.first_run);
+        results.rig = RigInfo::new(&self.usb_tty.port_name(),self.address);
+        results.target = self.target;
+        results.anomalies = self.anomalies;
+        results.pull_stats = self.pull_stats;
+        results.boot_times = self.boot_times;
+        results.bp_stats = self.bp_stats;
+        log::debug!("final data to write for {}: [{:?}]",self.serial,results);
+        if let Some(ref mut journal) = self.journal{
+            journal.sync();
+        }
+        if let Err(error) = results.save(OUTPUT_FOLDER){
+            log::warn!("Could not write results for {}! Potential permissions error.",&self.serial);
+            return Err(error)
+        }
+        return Ok(())
+    }
+    pub fn auto_set_serial(&mut self) -> Result<(),Error>{
         loop {
             match self.current_state {
                 State::LoginPrompt => {
-                    self.usb_tty.write_to_device(Command::Login);
-                    while self.usb_tty.read_from_device(None) != Response::ShellPrompt {};
-                    self.current_state = State::ShellPrompt;
+                    if let Err(error) = self.log_in(){
+                        log::error!("Unable to log in to device {:?}: {}",self.usb_tty,error);
+                        return Err(error)
+                    }
                 },
                 State::Shutdown => {
-                    while self.usb_tty.read_from_device(None) != Response::LoginPrompt{};
-                    self.current_state = State::LoginPrompt;
+                    if let Err(error) = self.wait_for_login_prompt(){
+                        log::error!("Device {:?} did not reach a login prompt: {}",self.usb_tty,error);
+                        return Err(error)
+                    }
                 },
                 State::DebugMenu | State::LifecycleMenu | State::BrightnessMenu => {
-                    self.usb_tty.write_to_device(Command::Quit);
-                    _ = self.usb_tty.read_from_device(None);
+                    self.usb_tty.write_to_device(Command::Quit)?;
+                    if self.read()? == Response::UBoot { continue; }
                     self.current_state = State::ShellPrompt;
                 },
                 State::ShellPrompt => {
-                    self.usb_tty.write_to_device(Command::GetSerial);
-                    loop{
-                        let return_value = self.usb_tty.read_from_device(None);
-                        match return_value{
-                            Response::Serial(Some(contains_serial)) =>{
-                                for line in contains_serial.split("\n").collect::<Vec<&str>>(){
-                                    if !line.contains(':') { continue; }
-                                    let (section,value) = line.split_once(':').unwrap();
-                                    if section.contains(SERIAL_HEADER){
-                                        self.serial = value.trim().replace("\"","");
-                                    }
+                    self.usb_tty.write_to_device(Command::GetSerial)?;
+                    let return_value = self.expect(&[Response::Serial(None),Response::FailedDebugMenu],SERIAL_TIMEOUT)?;
+                    match return_value{
+                        Response::Serial(Some(contains_serial)) =>{
+                            for line in contains_serial.split("\n").collect::<Vec<&str>>(){
+                                if !line.contains(':') { continue; }
+                                let (section,value) = line.split_once(':').unwrap();
+                                if section.contains(SERIAL_HEADER){
+                                    self.serial = value.trim().replace("\"","");
                                 }
-                                log::info!("Serial found for device {}",self.serial);
-                                break;
-                            },
-                            Response::DebugInit | Response::Empty | Response::EmptyNewline => { continue; }
-                            _ => {
-                                log::error!("Bad value: {:?}",return_value);
-                                return false
-                            },
-                        }
+                            }
+                            log::info!("Serial found for device {}",self.serial);
+                            self.usb_tty.rename_transcript(&self.serial);
+                        },
+                        Response::UBoot => continue,
+                        _ => {
+                            log::error!("Bad value: {:?}",return_value);
+                            return Err(Error::UnexpectedResponse(return_value))
+                        },
+                    }
+                    if let Err(error) = self.open_debug_menu(){
+                        log::error!("Unable to reopen debug menu on device {}: {}",self.serial,error);
+                        return Err(error)
                     }
-                    self.usb_tty.write_to_device(Command::DebugMenu);
-                    while self.usb_tty.read_from_device(None) != Response::DebugMenu {}
-                    self.current_state = State::DebugMenu;
-                    self.load_values();
-                    self.save_values();
-                    return true
+                    if let Err(error) = self.load_values(){
+                        log::warn!("Could not load values for device {}: {}",self.serial,error);
+                    }
+                    return self.save_values()
                 },
             }
         }
     }    
 
-    pub fn manual_set_serial(&mut self, serial:&str) -> &mut Self{
+    pub fn manual_set_serial(&mut self, serial:&str) -> Result<&mut Self,Error>{
         self.serial = serial.to_string();
-        self.load_values();
-        self.save_values();
-        return self;
+        self.usb_tty.rename_transcript(&self.serial);
+        if let Err(error) = self.load_values(){
+            log::warn!("Could not load values for device {}: {}",self.serial,error);
+        }
+        self.save_values()?;
+        return Ok(self);
+    }
+    fn has_serial(&self) -> bool{
+        return self.serial != UNINITIALISED_SERIAL;
     }
     pub fn get_serial(&self) -> &str{
         &self.serial
     }
+    pub fn get_reboots(&self) -> u64{
+        return self.reboots;
+    }
+    pub fn get_bps(&self) -> u64{
+        return self.bps;
+    }
+    pub fn get_target(&self) -> Targets{
+        return self.target;
+    }
+    //The target is saved with the results, so a later run carries on toward it
+    pub fn set_target(&mut self, target:Targets) -> Result<&mut Self,Error>{
+        self.target = target;
+        self.save_values()?;
+        return Ok(self);
+    }
+    pub fn get_profile(&self) -> Profile{
+        return self.profile;
+    }
+    pub fn set_profile(&mut self, profile:Profile) -> &mut Self{
+        self.profile = profile;
+        return self;
+    }
+    //Lets a supervisor see whether the unit is making progress, and interrupt it if not
+    pub fn supervise(&mut self, heartbeat:Heartbeat) -> &mut Self{
+        self.usb_tty.set_interrupt(heartbeat.interrupt_flag());
+        self.heartbeat = Some(heartbeat);
+        return self;
+    }
+    fn status(&self) -> UnitStatus{
+        return self.heartbeat.as_ref().map_or(UnitStatus::Running,|heartbeat| heartbeat.status());
+    }
+    fn set_status(&self, status:UnitStatus){
+        if let Some(ref heartbeat) = self.heartbeat{
+            heartbeat.set_status(status);
+        }
+    }
+    pub fn is_stalled(&self) -> bool{
+        return self.status() == UnitStatus::Stalled;
+    }
+    pub fn is_quarantined(&self) -> bool{
+        return self.status() == UnitStatus::Quarantined;
+    }
+    pub fn target_reached(&self) -> bool{
+        return self.target.reached(self.reboots,self.bps);
+    }
     pub fn get_location(&mut self) -> String{
         std::format!("{:?}",self.usb_tty)
     }
-    pub fn set_pin_address(&mut self, address:u8) -> &mut Self{
-        self.address = Some(address.clone());
-        let temp = self.gpio.get(address);
+    pub fn get_port(&self) -> String{
+        self.usb_tty.port_name()
+    }
+    pub fn set_pin_address(&mut self, address:u8) -> Result<&mut Self,Error>{
+        self.address = Some(address);
+        //Release the old relay first, so re-selecting the same pin doesn't find it busy
+        self.pin = None;
+        let temp = self.gpio.output(address);
         match temp{
-            Ok(pin) => self.pin = Some(pin.into_output()),
+            Ok(pin) => self.pin = Some(pin),
             Err(error) => {
                 log::warn!("Could not set pin to this address {}; already assigned?",address);
                 log::debug!("{}",error);
+                return Err(error);
             }
         }
-        return self;
+        return Ok(self);
     }
     pub fn start_temp(&mut self) -> &mut Self {
         if let Some(ref mut pin) = self.pin {
@@ -458,169 +656,518 @@ impl Device{
         }
         return self;
     }
-    fn start_bp(&mut self) -> &mut Self {
-        self.go_to_lifecycle_menu();
-        self.usb_tty.write_to_device(Command::StartBP);
-        _ = self.usb_tty.read_from_device(None);
-        return self;
+    fn start_bp(&mut self) -> Result<&mut Self,Error> {
+        self.go_to_lifecycle_menu()?;
+        self.usb_tty.write_to_device(Command::StartBP)?;
+        if self.read()? == Response::UBoot{
+            return Err(Error::UnexpectedResponse(Response::UBoot));
+        }
+        return Ok(self);
     }
-    pub fn darken_screen(&mut self) -> &mut Self {
-        self.go_to_brightness_menu();
-        self.usb_tty.write_to_device(Command::BrightnessLow);
-        _ = self.usb_tty.read_from_device(None);
-        return self;
+    pub fn darken_screen(&mut self) -> Result<&mut Self,Error> {
+        return self.set_brightness(false);
     }
-    pub fn brighten_screen(&mut self) -> &mut Self {
-        self.go_to_brightness_menu();
-        self.usb_tty.write_to_device(Command::BrightnessHigh);
-        _ = self.usb_tty.read_from_device(None);
-        return self;
+    pub fn brighten_screen(&mut self) -> Result<&mut Self,Error> {
+        return self.set_brightness(true);
+    }
+    //A unit that lands in U-Boot has lost its way to the menu, so the setting is tried again
+    //from the start
+    fn set_brightness(&mut self, high:bool) -> Result<&mut Self,Error> {
+        for _ in 0..BRIGHTNESS_ATTEMPTS{
+            self.go_to_brightness_menu()?;
+            self.usb_tty.write_to_device(if high { Command::BrightnessHigh } else { Command::BrightnessLow })?;
+            if self.read()? != Response::UBoot{
+                return Ok(self);
+            }
+        }
+        log::warn!("Device {} kept landing in U-Boot while setting its brightness",self.serial);
+        return Err(Error::UnexpectedResponse(Response::UBoot));
     }
 
-    pub fn is_temp_running(&mut self) -> bool{
-        self.go_to_lifecycle_menu();
-        self.usb_tty.write_to_device(Command::ReadTemp);
-        for _ in 0..10 {
-            match self.usb_tty.read_from_device(None){
-                Response::TempCount(Some(count)) => return count != self.init_temps ,
-                _ => {},
+    //Asks for the temp count, allowing a few unrelated responses and one retry before
+    //giving up on the device
+    fn read_temp_count(&mut self) -> Result<u64,Error>{
+        let mut last = Response::Empty;
+        for _ in 0..2 {
+            self.go_to_lifecycle_menu()?;
+            self.usb_tty.write_to_device(Command::ReadTemp)?;
+            for _ in 0..10 {
+                match self.read()?{
+                    Response::TempCount(Some(count)) => return Ok(count),
+                    Response::Empty => {},
+                    //Booted back to the login prompt, so the menu has to be found again
+                    Response::UBoot => break,
+                    other => last = other,
+                }
             }
         }
-	self.usb_tty.write_to_device(Command::ReadTemp);
-	for _ in 0..10{
-	    match self.usb_tty.read_from_device(None){
-                Response::TempCount(Some(count)) => return count != self.init_temps ,
-		_ => {},
-	    }
-        }
-	log::error!("Temp read failed!!!");
-        return false
-    }
-
-    pub fn update_temp_count(&mut self) -> u64 {
-        self.go_to_lifecycle_menu();
-        self.usb_tty.write_to_device(Command::ReadTemp);
-        for _ in 0..10 {
-            match self.usb_tty.read_from_device(None){
-                Response::TempCount(Some(count)) => {
-                    log::trace!("Count for device {} updated to {}",self.serial,count);
-                    self.temps = count;
-                    return count
-                },
-                _ => {},
+        return Err(Error::UnexpectedResponse(last));
+    }
+
+    pub fn is_temp_running(&mut self) -> Result<bool,Error>{
+        match self.read_temp_count(){
+            Ok(count) => return Ok(count != self.init_temps),
+            Err(error) => {
+                log::error!("Temp read failed!!!");
+                return Err(error)
             }
         }
-	self.usb_tty.write_to_device(Command::ReadTemp);
-	for _ in 0..10{
-	    match self.usb_tty.read_from_device(None){
-                Response::TempCount(Some(count)) => {
-                    log::trace!("Count for device {} updated to {}",self.serial,count);
-                    self.temps = count;
-                    return count
-                },
-		_ => {},
-	    }
-        }
-	log::error!("Update temp count on device {} failed!!!",self.serial);
-	return 0;
-    }
-
-    pub fn init_temp_count(&mut self){
-        self.go_to_lifecycle_menu();
-        self.usb_tty.write_to_device(Command::ReadTemp);
-        for _ in 0..10 {
-            match self.usb_tty.read_from_device(None){
-                Response::TempCount(Some(count)) => {
-                    log::trace!("init temp count set to {} on device {}",count,self.serial);
-                    self.init_temps = count;
-                    return
-                },
-                _ => {},
+    }
+
+    pub fn update_temp_count(&mut self) -> Result<u64,Error> {
+        match self.read_temp_count(){
+            Ok(count) => {
+                log::trace!("Count for device {} updated to {}",self.serial,count);
+                let counted_before = self.temps.saturating_sub(self.init_temps);
+                self.temps = count;
+                let pulls = self.temps.saturating_sub(self.init_temps).saturating_sub(counted_before);
+                self.record(Event::TempCountUpdated{ count, pulls },None);
+                return Ok(count)
+            },
+            Err(error) => {
+                log::error!("Update temp count on device {} failed!!!",self.serial);
+                return Err(error)
             }
-        };
-	self.usb_tty.write_to_device(Command::ReadTemp);
-	for _ in 0..10{
-	    match self.usb_tty.read_from_device(None){
-                Response::TempCount(Some(count)) => {
-                    log::trace!("init temp count set to {} on device {}",count,self.serial);
-                    self.init_temps = count;
-                    return
-                },
-		_ => {},
-	    }
-        };
-	log::error!("init temp count failed on device {}!!!",self.serial);
+        }
     }
 
-    fn is_bp_running(&mut self) -> bool {
-        self.go_to_lifecycle_menu();
-        self.usb_tty.write_to_device(Command::CheckBPState);
-        loop { 
-            match self.usb_tty.read_from_device(None){
-                Response::BPOn => return true,
-                Response::BPOff => return false,
-                _ => return false,
+    pub fn init_temp_count(&mut self) -> Result<(),Error>{
+        match self.read_temp_count(){
+            Ok(count) => {
+                log::trace!("init temp count set to {} on device {}",count,self.serial);
+                self.init_temps = count;
+                return Ok(())
+            },
+            Err(error) => {
+                log::error!("init temp count failed on device {}!!!",self.serial);
+                return Err(error)
+            }
+        }
+    }
+
+    //None if the unit didn't say, even when asked twice. A unit that rebooted instead of
+    //answering gives an UnexpectedResponse error with whatever it sent.
+    fn is_bp_running(&mut self) -> Result<Option<bool>,Error> {
+        for _ in 0..2 {
+            self.go_to_lifecycle_menu()?;
+            self.usb_tty.write_to_device(Command::CheckBPState)?;
+            match self.expect(&[Response::BPOn,Response::BPOff,Response::Rebooting,Response::LoginPrompt],BP_CHECK_TIMEOUT){
+                Ok(Response::BPOn) => return Ok(Some(true)),
+                Ok(Response::BPOff) => return Ok(Some(false)),
+                Ok(other) => return Err(Error::UnexpectedResponse(other)),
+                Err(Error::Timeout{..}) => log::warn!("Device {} didn't say whether its BP is running",self.serial),
+                Err(error) => return Err(error),
             }
         }
+        return Ok(None);
     }
-    pub fn reboot(&mut self) -> () {
-        self.usb_tty.write_to_device(Command::Quit);
-        self.usb_tty.write_to_device(Command::Reboot);
+    pub fn reboot(&mut self) -> Result<(),Error> {
+        let start = Instant::now();
+        self.record(Event::RebootStarted,None);
+        match self.restart(){
+            Ok(counted) => {
+                self.record(Event::RebootFinished{ counted },Some(start.elapsed()));
+                return Ok(());
+            },
+            Err(error) => {
+                self.record(Event::RebootFailed{ error: error.to_string() },Some(start.elapsed()));
+                return Err(error);
+            }
+        }
+    }
+
+    //Reboots the unit and waits for it to come back, returning whether the reboot was seen
+    fn restart(&mut self) -> Result<bool,Error> {
+        self.usb_tty.write_to_device(Command::Quit)?;
+        //Booting from U-Boot has already done the reboot's job, though it doesn't count as one
+        if self.read()? == Response::UBoot { return Ok(false); }
+        self.usb_tty.write_to_device(Command::Reboot)?;
+        self.boot_clock = Some(BootClock::start());
         let mut successful_reboot:bool = false;
         //let mut exited_menu:bool = false;
+        let start = Instant::now();
         loop{
-            match self.usb_tty.read_from_device(None){
-                Response::LoginPrompt => break,
-                Response::Rebooting => {
+            let remaining = BOOT_TIMEOUT.saturating_sub(start.elapsed());
+            match self.expect(&[Response::LoginPrompt,Response::Rebooting,Response::ShuttingDown],remaining){
+                Ok(Response::Rebooting) => {
                     log::trace!("Successful reboot detected for device {}.",self.serial);
+                    self.mark_boot(BootPhase::KernelRestart);
                     successful_reboot = true;
                     //This error message is turning out to be more false positive than anything
                     //else. Reboots can sometimes dump both reboot flag and shutdown flag at once.
                     //if !exited_menu { log::info!("Unusual reboot detected for device {}. Please check logs.",self.serial); }
                 },
-                Response::ShuttingDown => {
+                Ok(Response::ShuttingDown) => {
                     log::trace!("Exiting debug menu on device {}.",self.serial);
                     //exited_menu = true;
                 },
-                _ => {}
+                Ok(_) => break,
+                Err(error) => {
+                    log::error!("Device {} did not come back from reboot: {}",self.serial,error);
+                    self.finish_boot_timing(false);
+                    self.current_state = State::Shutdown;
+                    return Err(error);
+                }
             }
         };
         if successful_reboot { self.reboots += 1; }
+        self.mark_boot(BootPhase::LoginPrompt);
         self.current_state = State::LoginPrompt;
+        return Ok(successful_reboot);
+    }
+
+    //Leaves the unit safe to walk away from: relay open, counts saved, and optionally logged
+    //in at the shell rather than sat in the debug menu
+    pub fn stop(&mut self, return_to_shell:bool) -> Result<(),Error>{
+        self.stop_temp();
+        self.finish_boot_timing(false);
+        //Pulls since the start of the cycle are only on the unit's counter so far. It's only
+        //worth asking if the unit is already in a menu, rather than waiting out a reboot.
+        if matches!(self.current_state,State::DebugMenu | State::LifecycleMenu | State::BrightnessMenu){
+            if let Err(error) = self.update_temp_count(){
+                log::warn!("Unable to read final temp count for device {}: {}",self.serial,error);
+            }
+        }
+        let saved = self.save_values();
+        let mut returned_to_shell = false;
+        if return_to_shell{
+            match self.return_to_shell(){
+                Ok(()) => returned_to_shell = true,
+                Err(error) => log::warn!("Unable to return device {} to the shell: {}",self.serial,error),
+            }
+        }
+        self.record(Event::Stopped{ returned_to_shell },None);
+        return saved;
+    }
+
+    fn return_to_shell(&mut self) -> Result<(),Error>{
+        loop{
+            match self.current_state{
+                State::ShellPrompt => return Ok(()),
+                State::DebugMenu | State::LifecycleMenu | State::BrightnessMenu => {
+                    self.usb_tty.write_to_device(Command::Quit)?;
+                    if self.read()? == Response::UBoot { continue; }
+                    self.current_state = State::ShellPrompt;
+                },
+                State::LoginPrompt => self.log_in()?,
+                State::Shutdown => self.wait_for_login_prompt()?,
+            }
+        }
+    }
+
+    //Works through RECOVERY_STEPS until the unit's temp count can be read again. If none of
+    //them work, the unit is quarantined with its relay open and its counts saved.
+    pub fn recover(&mut self) -> Result<(),Error>{
+        self.stop_temp();
+        //Whatever the unit was booting into, recovery starts it over
+        self.finish_boot_timing(false);
+        self.record(Event::Stalled,None);
+        self.set_status(UnitStatus::Recovering);
+        let mut last_error = Error::Stalled;
+        for step in RECOVERY_STEPS{
+            //The supervisor has given up waiting, so nothing more is sent to the unit
+            if self.is_quarantined() { break; }
+            log::info!("Trying to recover device {} with a {}",self.serial,step.name());
+            if let Some(ref heartbeat) = self.heartbeat{
+                heartbeat.beat();
+            }
+            match self.try_recovery_step(step).and_then(|_| self.read_temp_count()){
+                Ok(_) => {
+                    log::info!("Device {} recovered after a {}",self.serial,step.name());
+                    self.record(Event::Recovered{ step: step.name().to_string() },None);
+                    self.set_status(UnitStatus::Running);
+                    //The timing closed as recovery started is only kept once saved
+                    if let Err(error) = self.save_values(){
+                        log::warn!("Unable to save results for device {} after recovering it: {}",self.serial,error);
+                    }
+                    return Ok(());
+                },
+                Err(error) => {
+                    log::warn!("Device {} did not recover after a {}: {}",self.serial,step.name(),error);
+                    last_error = error;
+                }
+            }
+        }
+        self.finish_boot_timing(false);
+        self.record(Event::Quarantined{ error: last_error.to_string() },None);
+        self.set_status(UnitStatus::Quarantined);
+        if let Err(error) = self.save_values(){
+            log::warn!("Unable to save results for quarantined device {}: {}",self.serial,error);
+        }
+        return Err(last_error);
+    }
+
+    fn try_recovery_step(&mut self, step:RecoveryStep) -> Result<(),Error>{
+        match step{
+            RecoveryStep::Newline => {
+                self.usb_tty.write_to_device(Command::Newline)?;
+                let response = self.expect(&[Response::LoginPrompt,Response::PasswordPrompt,Response::ShellPrompt,Response::PreShellPrompt,Response::DebugMenu],MENU_TIMEOUT)?;
+                if response == Response::UBoot { return Ok(()); }
+                if let Some(state) = self.current_state.after_response(&response){
+                    self.current_state = state;
+                }
+            },
+            RecoveryStep::QuitMenu => {
+                self.usb_tty.write_to_device(Command::Quit)?;
+                let response = self.expect(&[Response::ShellPrompt,Response::ShuttingDown,Response::LoginPrompt,Response::Rebooting],MENU_TIMEOUT)?;
+                if response == Response::UBoot { return Ok(()); }
+                self.current_state = self.current_state.after_response(&response).unwrap_or(State::ShellPrompt);
+            },
+            RecoveryStep::Reboot => self.reboot()?,
+            RecoveryStep::UBootBoot => {
+                self.usb_tty.write_to_device(Command::Boot)?;
+                self.usb_tty.expect(&[Response::LoginPrompt],BOOT_TIMEOUT)?;
+                self.current_state = State::LoginPrompt;
+            },
+        }
+        return Ok(());
     }
 
-    pub fn test_cycle(&mut self, bp_cycles: Option<u64>) -> () {
-        let local_bp_cycles: u64 = bp_cycles.unwrap_or(3);
-        if self.current_state != State::LoginPrompt { self.reboot(); }
-        self.go_to_lifecycle_menu();
-        _ = self.usb_tty.read_from_device(Some("["));
-        self.update_temp_count();
+    //Called once the unit has no more cycles to run. The last reboot of a run is never followed
+    //by the debug menu, so it is recorded with the phases seen so far.
+    pub fn finish_run(&mut self){
+        self.stop_temp();
+        self.finish_boot_timing(false);
+        if let Err(error) = self.save_values(){
+            log::warn!("Unable to save results for device {} at the end of its run: {}",self.serial,error);
+        }
+    }
+
+    //Runs one cycle of the unit's profile, with `bp_cycles` in place of its BPs per reboot if
+    //given. Stops between steps once a shutdown has been requested, without rebooting. A BP
+    //that has already started is seen through to the end.
+    pub fn test_cycle(&mut self, bp_cycles: Option<u64>) -> Result<(),Error> {
+        let local_bp_cycles: u64 = bp_cycles.unwrap_or(self.profile.bps_per_reboot);
+        if shutdown::requested() { return Ok(()); }
+        if self.profile.reboot_every_cycle && self.current_state != State::LoginPrompt { self.reboot()?; }
+        self.go_to_lifecycle_menu()?;
+        self.update_temp_count()?;
         for _bp_count in 1..=local_bp_cycles{
+            if shutdown::requested() { return Ok(()); }
             log::info!("Running bp {} on device {} ...",(self.bps+1),self.serial);
-            self.start_bp();
-            let bp_start:bool = self.is_bp_running();
-            log::trace!("Has bp started on device {}? : {:?}",self.serial,bp_start);
-
-            if bp_start{
-                log::trace!("Starting temp on device {}",self.serial);
-                self.start_temp();
-                thread::sleep(TEMP_WAIT);
-                log::trace!("Stopping temp on device {}",self.serial);
-                self.stop_temp();
+            let bp_started_at = Instant::now();
+            let mut polls = 0;
+            let outcome = match self.run_bp(&mut polls){
+                Ok(outcome) => outcome,
+                //Once the unit is back up, the cycle carries on with the next BP
+                Err(Error::UnexpectedResponse(response)) if matches!(response,Response::UBoot | Response::Rebooting | Response::LoginPrompt) => {
+                    log::warn!("BP on device {} was cut short by a reboot ({:?})",self.serial,response);
+                    //Booting from U-Boot has already left the unit at its login prompt
+                    if response != Response::UBoot{
+                        self.current_state = self.current_state.after_response(&response).unwrap_or(State::Shutdown);
+                    }
+                    BpOutcome::AbortedByReboot
+                },
+                Err(error) => return Err(error),
             };
+            self.finish_bp(outcome,polls,bp_started_at.elapsed())?;
+            if outcome == BpOutcome::TimedOut{
+                //Leaving the menu and rebooting is the only way to stop a pump that won't
+                if shutdown::requested() { return Ok(()); }
+                log::warn!("Rebooting device {} to clear its stuck BP",self.serial);
+                self.reboot()?;
+            }
+        }
+        if shutdown::requested() { return Ok(()); }
+        //A unit rebooted to clear a stuck BP has already had this cycle's reboot
+        if self.profile.reboot_every_cycle && self.current_state != State::LoginPrompt{
+            log::info!("Rebooting {} for the {}th time",self.serial, self.reboots);
+            self.reboot()?;
+        }
+        self.save_values()?;
+        return Ok(());
+    }
+
+    //Returns how the BP ended, counting every check made while waiting for it in `polls`
+    fn run_bp(&mut self, polls:&mut u64) -> Result<BpOutcome,Error>{
+        self.start_bp()?;
+        self.record(Event::BpStarted,None);
+        let bp_start:bool = self.is_bp_running()? == Some(true);
+        log::trace!("Has bp started on device {}? : {:?}",self.serial,bp_start);
 
-            while self.is_bp_running() {};
+        if bp_start{
+            log::trace!("Starting temp on device {}",self.serial);
+            let pull_start = Instant::now();
+            self.start_temp();
+            thread::sleep(self.profile.temp_pull());
+            log::trace!("Stopping temp on device {}",self.serial);
+            self.stop_temp();
+            let pull_duration = pull_start.elapsed();
+            let counted_before = self.temps;
+            let registered = self.update_temp_count()? > counted_before;
+            self.record(Event::TempPull{ registered: Some(registered) },Some(pull_duration));
+            self.check_pull(registered);
+        };
 
-            let bp_end = self.is_bp_running();
-            log::trace!("Has bp ended on device {}? : {:?}",self.serial,bp_end);
-            if bp_start != bp_end {
-                self.bps +=1;
-                log::trace!("Increasing bp count for device {} to {}",self.serial,self.bps);
-                self.save_values();
+        //A check the unit doesn't answer is put down to it being busy, so only the deadline can
+        //fail a BP that has started
+        let bp_deadline = Instant::now() + self.profile.max_bp();
+        loop {
+            *polls += 1;
+            let running = self.is_bp_running()?;
+            if running == Some(false) { break; }
+            if Instant::now() >= bp_deadline{
+                log::error!("BP on device {} still running after {:?}, giving up on it",self.serial,self.profile.max_bp());
+                return Ok(BpOutcome::TimedOut);
+            }
+            //The unit is answering, so a long BP isn't a stall. The deadline deals with it instead.
+            if running.is_some(){
+                if let Some(ref heartbeat) = self.heartbeat{
+                    heartbeat.beat();
+                }
             }
+            thread::sleep(self.profile.bp_poll());
+        };
+        log::trace!("BP has ended on device {}",self.serial);
+        if !bp_start { return Ok(BpOutcome::NeverStarted); }
+        return Ok(BpOutcome::Completed);
+    }
+
+    //Keeps track of pulls that didn't register, flagging the unit once too many are missed in a row
+    fn check_pull(&mut self, registered:bool){
+        if registered{
+            if self.pull_stats.fault{
+                log::info!("Pulls are registering on device {} again",self.serial);
+            }
+            self.pull_stats.registered += 1;
+            self.pull_stats.consecutive_missed = 0;
+            self.pull_stats.fault = false;
+            return;
+        }
+        self.pull_stats.missed += 1;
+        self.pull_stats.consecutive_missed += 1;
+        log::warn!("Pull on device {} wasn't registered ({} in a row)",self.serial,self.pull_stats.consecutive_missed);
+        if self.pull_stats.consecutive_missed == MISSED_PULLS_FOR_FAULT{
+            let pin = self.address.map_or("unknown".to_string(),|address| address.to_string());
+            log::error!("Device {} has missed {} pulls in a row. Check its relay on pin {} and its probe well.",self.serial,MISSED_PULLS_FOR_FAULT,pin);
+            self.pull_stats.fault = true;
+            self.record(Event::PullFault{ missed: self.pull_stats.consecutive_missed },None);
+        }
+    }
+
+    fn finish_bp(&mut self, outcome:BpOutcome, polls:u64, duration:Duration) -> Result<(),Error>{
+        let counted = outcome == BpOutcome::Completed;
+        log::debug!("BP on device {} finished as {:?} after {:?} and {} polls",self.serial,outcome,duration,polls);
+        self.record(Event::BpFinished{ counted, outcome: Some(outcome), polls: Some(polls) },Some(duration));
+        self.bp_stats.add(outcome);
+        if counted {
+            self.bps +=1;
+            log::trace!("Increasing bp count for device {} to {}",self.serial,self.bps);
+            self.bp_durations.push(duration.as_millis() as u64);
+            self.bp_stats.duration = TimingStats::from_samples(&self.bp_durations);
+        }
+        return self.save_values();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::gpio_facade::MockGpio;
+    use crate::simulator::{FaultScript, Simulator, SimulatorConfig};
+    use crate::supervisor::Supervisor;
+    use crate::transport::MemoryTransport;
+    use std::sync::mpsc;
+
+    const RELAY_PIN:u8 = 4;
+
+    //A simulated unit at its login prompt. Its serial is never set, so nothing is saved.
+    fn simulated_device(bp_duration:Duration, wired:bool) -> Device{
+        return scripted_device(bp_duration,"",wired).0;
+    }
+
+    //As `simulated_device`, with faults injected from a fault script. The relays are returned
+    //so their toggles can be checked.
+    fn scripted_device(bp_duration:Duration, script:&str, wired:bool) -> (Device,MockGpio){
+        let (local, remote) = MemoryTransport::pair("sim/test");
+        let gpio = MockGpio::new();
+        let config = SimulatorConfig{
+            boot_time: Duration::from_millis(200),
+            bp_duration,
+            seed: 1,
+            script: FaultScript::parse(script).unwrap(),
+            ..Default::default()
+        };
+        let simulator = Simulator::new(Box::new(remote),config);
+        if wired{
+            gpio.connect(RELAY_PIN,simulator.temp_counter());
         }
-        log::info!("Rebooting {} for the {}th time",self.serial, self.reboots);
-        self.reboot();
-        self.save_values();
+        thread::spawn(move || simulator.run());
+        let mut device = Device::new(TTY::from_transport(Box::new(local)),None,Arc::new(gpio.clone())).unwrap();
+        device.set_pin_address(RELAY_PIN).unwrap();
+        device.set_profile(Profile{ temp_pull_seconds: 0.0, max_bp_seconds: 0.5, bp_poll_seconds: 0.1, ..Default::default() });
+        device.init_temp_count().unwrap();
+        return (device,gpio);
+    }
+
+    #[test]
+    fn bp_still_running_at_its_deadline_times_out(){
+        let mut device = simulated_device(Duration::from_secs(60),true);
+        let mut polls = 0;
+        assert_eq!(device.run_bp(&mut polls).unwrap(),BpOutcome::TimedOut);
+        assert!(polls > 1);
+    }
+
+    #[test]
+    fn pulls_are_checked_against_the_unit_counter(){
+        let (mut device,gpio) = scripted_device(Duration::from_millis(200),"",true);
+        assert_eq!(device.run_bp(&mut 0).unwrap(),BpOutcome::Completed);
+        assert_eq!((device.pull_stats.registered,device.pull_stats.missed),(1,0));
+        //The probe is pulled by closing the relay and released by opening it again
+        let relay:Vec<bool> = gpio.toggles().iter().filter(|toggle| toggle.pin == RELAY_PIN).map(|toggle| toggle.high).collect();
+        assert_eq!(relay,vec![true,false]);
+    }
+
+    #[test]
+    fn missed_pulls_in_a_row_flag_a_fault(){
+        let mut device = simulated_device(Duration::from_millis(200),false);
+        for _ in 0..MISSED_PULLS_FOR_FAULT{
+            assert!(!device.pull_stats.fault);
+            assert_eq!(device.run_bp(&mut 0).unwrap(),BpOutcome::Completed);
+        }
+        assert_eq!((device.pull_stats.registered,device.pull_stats.missed),(0,MISSED_PULLS_FOR_FAULT));
+        assert!(device.pull_stats.fault);
+        device.check_pull(true);
+        assert!(!device.pull_stats.fault);
+    }
+
+    //Runs cycles the way a unit's thread does in a run, recovering it when it stalls
+    fn run_until_quarantined(mut device:Device, finished:mpsc::Sender<()>) -> bool{
+        loop{
+            _ = device.test_cycle(None);
+            if device.is_stalled() && device.recover().is_err() { break; }
+            if device.is_quarantined() { break; }
+        }
+        device.finish_run();
+        _ = finished.send(());
+        return !device.is_quarantined();
+    }
+
+    #[test]
+    fn hung_unit_is_quarantined_with_its_relay_open(){
+        let (mut device, gpio) = scripted_device(Duration::from_millis(200),"20 hang",true);
+        let heartbeat = Heartbeat::new();
+        device.supervise(heartbeat.clone());
+        let (finished, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || run_until_quarantined(device,finished));
+        let mut supervisor = Supervisor::new(Duration::from_secs(1),Duration::from_secs(1));
+        supervisor.watch("sim/test",heartbeat.clone(),thread);
+
+        assert!(!supervisor.wait());
+        assert_eq!(heartbeat.status(),UnitStatus::Quarantined);
+        stopped.recv_timeout(Duration::from_secs(30)).unwrap();
+        let relay = gpio.toggles().into_iter().rfind(|toggle| toggle.pin == RELAY_PIN).unwrap();
+        assert!(!relay.high);
+    }
+
+    #[test]
+    fn unit_landing_in_uboot_on_reboot_carries_on_with_the_cycle(){
+        let (mut device, _) = scripted_device(Duration::from_millis(200),"1 uboot",true);
+        device.test_cycle(None).unwrap();
+        assert_eq!(device.anomalies.uboot_prompts,1);
+        assert_eq!(device.bps,device.profile.bps_per_reboot);
+        //The unit restarted before landing in U-Boot, so that reboot still counts
+        assert_eq!(device.reboots,2);
+        assert_eq!(device.current_state,State::LoginPrompt);
     }
 }
@@ -0,0 +1,78 @@
+use std::{fmt, io, time::Duration};
+use crate::tty::Response;
+
+//Everything that can go wrong talking to a unit. Anything built on the library can match on
+//this instead of reading the logs.
+#[derive(Debug)]
+pub enum Error{
+    //The device didn't send any of the expected responses in time
+    Timeout{
+        expected: Vec<Response>,
+        waited: Duration,
+        last: Option<Response>,
+    },
+    //The device answered, but with something that makes no sense in the current state
+    UnexpectedResponse(Response),
+    //Whichever GPIO backend is in use failed to open the chip or a line
+    Gpio(Box<dyn std::error::Error + Send + Sync>),
+    //Saving or loading a device's counts failed
+    Persistence{
+        path: String,
+        source: io::Error,
+    },
+    SerialIo(io::Error),
+    //Settings that can't work on this bench
+    Config(String),
+    //The supervisor gave up waiting on the device and interrupted whatever it was doing
+    Stalled,
+}
+
+impl fmt::Display for Error{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            Error::Timeout{expected,waited,last} => {
+                write!(f,"timed out after {:?} waiting for {:?}",waited,expected)?;
+                if let Some(last) = last{
+                    write!(f,", last response was {:?}",last)?;
+                }
+                return Ok(());
+            },
+            Error::UnexpectedResponse(response) => write!(f,"unexpected response {:?}",response),
+            Error::Gpio(error) => write!(f,"GPIO error: {}",error),
+            Error::Persistence{path,source} => write!(f,"unable to access {}: {}",path,source),
+            Error::SerialIo(error) => write!(f,"serial I/O error: {}",error),
+            Error::Config(message) => write!(f,"invalid configuration: {}",message),
+            Error::Stalled => write!(f,"interrupted after the device stopped making progress"),
+        }
+    }
+}
+
+impl std::error::Error for Error{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+        match self{
+            Error::Gpio(error) => Some(error.as_ref()),
+            Error::Persistence{source,..} => Some(source),
+            Error::SerialIo(error) => Some(error),
+            Error::Timeout{..} | Error::UnexpectedResponse(_) | Error::Config(_) | Error::Stalled => None,
+        }
+    }
+}
+
+impl From<rppal::gpio::Error> for Error{
+    fn from(error:rppal::gpio::Error) -> Self{
+        Error::Gpio(Box::new(error))
+    }
+}
+
+impl From<gpio_cdev::Error> for Error{
+    fn from(error:gpio_cdev::Error) -> Self{
+        Error::Gpio(Box::new(error))
+    }
+}
+
+impl From<serialport::Error> for Error{
+    fn from(error:serialport::Error) -> Self{
+        Error::SerialIo(error.into())
+    }
+}
+
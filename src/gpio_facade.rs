@@ -1,32 +1,208 @@
-use rppal::gpio::Gpio;
+use std::{collections::HashMap,
+          fmt::Debug,
+          ops::RangeInclusive,
+          sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+          time::Instant};
+use rppal::gpio::{Gpio, OutputPin};
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+use crate::error::Error;
 
-const RELAY_ADDRESSES: [u8;10] = [4,5,6,12,13,17,18,19,20,26];
+pub const DEFAULT_RELAY_ADDRESSES: [u8;10] = [4,5,6,12,13,17,18,19,20,26];
+//BCM 0 and 1 are wired to the HAT ID EEPROM, and nothing above 27 reaches the header
+pub const USABLE_GPIO: RangeInclusive<u8> = 2..=27;
+pub const DEFAULT_GPIO_CHIP: &str = "/dev/gpiochip0";
+//Shown as the owner of each requested line, for example by gpioinfo
+const CDEV_CONSUMER: &str = "seymour_life";
+
+//One relay, driving a probe well
+pub trait RelayOutput: Send + Debug{
+    fn set_high(&mut self);
+    fn set_low(&mut self);
+}
+
+//Anything that can hand out relays by BCM pin number
+pub trait GpioBackend: Send + Sync + Debug{
+    fn output(&self, pin:u8) -> Result<Box<dyn RelayOutput>,Error>;
+
+    //Pins that can be driven at all on this backend
+    fn usable_pins(&self) -> RangeInclusive<u8>{
+        return USABLE_GPIO;
+    }
+}
+
+#[derive(Debug)]
+pub struct RppalGpio{
+    gpio: Gpio,
+}
+
+impl RppalGpio{
+    pub fn new() -> Result<Self,Error>{
+        match Gpio::new(){
+            Ok(gpio) => return Ok(Self{ gpio }),
+            Err(error) => {
+                log::warn!("Unable to open GPIO!");
+                log::debug!("{}",error);
+                return Err(error.into());
+            }
+        }
+    }
+}
+
+impl GpioBackend for RppalGpio{
+    fn output(&self, pin:u8) -> Result<Box<dyn RelayOutput>,Error>{
+        let pin = self.gpio.get(pin)?;
+        return Ok(Box::new(pin.into_output()));
+    }
+}
+
+impl RelayOutput for OutputPin{
+    fn set_high(&mut self){
+        OutputPin::set_high(self);
+    }
+    fn set_low(&mut self){
+        OutputPin::set_low(self);
+    }
+}
+
+//Relays on a kernel GPIO character device, such as /dev/gpiochip0. Unlike rppal this doesn't
+//need root, only access to the device node, and works on any board with a gpiochip driver.
+//Pin numbers are line offsets on the chip, which match BCM numbers on a Pi's main chip.
+#[derive(Debug)]
+pub struct CdevGpio{
+    chip: Mutex<Chip>,
+}
+
+impl CdevGpio{
+    pub fn new(path:&str) -> Result<Self,Error>{
+        match Chip::new(path){
+            Ok(chip) => {
+                log::debug!("Opened GPIO chip {} ({}, {} lines)",path,chip.label(),chip.num_lines());
+                return Ok(Self{ chip: Mutex::new(chip) });
+            },
+            Err(error) => {
+                log::warn!("Unable to open GPIO chip {}!",path);
+                log::debug!("{}",error);
+                return Err(error.into());
+            }
+        }
+    }
+}
+
+impl GpioBackend for CdevGpio{
+    fn output(&self, pin:u8) -> Result<Box<dyn RelayOutput>,Error>{
+        let line = self.chip.lock().unwrap().get_line(pin as u32)?;
+        let handle = line.request(LineRequestFlags::OUTPUT,0,CDEV_CONSUMER)?;
+        return Ok(Box::new(CdevRelay{ pin, handle }));
+    }
+
+    fn usable_pins(&self) -> RangeInclusive<u8>{
+        let lines = self.chip.lock().unwrap().num_lines().clamp(1,u8::MAX as u32 + 1);
+        return 0..=(lines - 1) as u8;
+    }
+}
+
+#[derive(Debug)]
+struct CdevRelay{
+    pin: u8,
+    handle: LineHandle,
+}
+
+impl CdevRelay{
+    fn set(&mut self, value:u8){
+        if let Err(error) = self.handle.set_value(value){
+            log::warn!("Unable to set GPIO line {} to {}: {}",self.pin,value,error);
+        }
+    }
+}
+
+impl RelayOutput for CdevRelay{
+    fn set_high(&mut self){
+        self.set(1);
+    }
+    fn set_low(&mut self){
+        self.set(0);
+    }
+}
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub struct RelayToggle{
+    pub pin: u8,
+    pub high: bool,
+    pub at: Instant,
+}
+
+//Relays that only exist in memory. Every toggle is recorded, and a pin can be wired to a
+//simulated unit's probe pull counter so that closing the relay counts as a pull.
+#[derive(Clone,Default,Debug)]
+pub struct MockGpio{
+    toggles: Arc<Mutex<Vec<RelayToggle>>>,
+    wiring: Arc<Mutex<HashMap<u8,Arc<AtomicU64>>>>,
+}
+
+impl MockGpio{
+    pub fn new() -> Self{
+        return Self::default();
+    }
+
+    pub fn connect(&self, pin:u8, temp_counter:Arc<AtomicU64>){
+        self.wiring.lock().unwrap().insert(pin,temp_counter);
+    }
+
+    pub fn toggles(&self) -> Vec<RelayToggle>{
+        return self.toggles.lock().unwrap().clone();
+    }
+}
+
+impl GpioBackend for MockGpio{
+    fn output(&self, pin:u8) -> Result<Box<dyn RelayOutput>,Error>{
+        return Ok(Box::new(MockRelay{ pin, high: false, gpio: self.clone() }));
+    }
+}
+
+#[derive(Debug)]
+struct MockRelay{
+    pin: u8,
+    high: bool,
+    gpio: MockGpio,
+}
+
+impl MockRelay{
+    fn set(&mut self, high:bool){
+        self.gpio.toggles.lock().unwrap().push(RelayToggle{ pin: self.pin, high, at: Instant::now() });
+        if high && !self.high{
+            if let Some(counter) = self.gpio.wiring.lock().unwrap().get(&self.pin){
+                counter.fetch_add(1,Ordering::SeqCst);
+            }
+        }
+        self.high = high;
+    }
+}
+
+impl RelayOutput for MockRelay{
+    fn set_high(&mut self){
+        self.set(true);
+    }
+    fn set_low(&mut self){
+        self.set(false);
+    }
+}
 
 pub struct GpioPins{
     unassigned_addresses:Vec<u8>
 }
 
 impl GpioPins{
-    pub fn new() -> Self {
+    pub fn new(gpio:&dyn GpioBackend, addresses:&[u8]) -> Self {
         let mut output = Self { unassigned_addresses:Vec::new() };
-        for pin in RELAY_ADDRESSES.iter(){
-            let possible_gpio = Gpio::new();
-            match possible_gpio{
-                Ok(gpio_object) =>{
-                    let temp = gpio_object.get(pin.clone());
-                    match temp{
-                        Ok(pin_object)=>{
-                            _ = pin_object.into_output_low();
-                            output.unassigned_addresses.push(*pin);
-                        },
-                        Err(error) => {
-                            log::warn!("Pin unavailable!");
-                            log::debug!("{}",error);
-                        }
-                    }
+        for pin in addresses.iter(){
+            let temp = gpio.output(*pin);
+            match temp{
+                Ok(mut pin_object)=>{
+                    pin_object.set_low();
+                    output.unassigned_addresses.push(*pin);
                 },
                 Err(error) => {
-                    log::warn!("Unable to open GPIO!");
+                    log::warn!("Pin unavailable!");
                     log::debug!("{}",error);
                 }
             }
@@ -43,3 +219,45 @@ impl GpioPins{
         return &self.unassigned_addresses;
     }
 }
+
+//Checks a bench's relay pins against the backend's usable range and removes any reserved ones.
+//Listing a pin as both a relay and reserved is treated as a mistake rather than silently
+//dropping it.
+pub fn validate_pins(gpio:&dyn GpioBackend, addresses:&[u8], reserved:&[u8]) -> Result<Vec<u8>,Error>{
+    let usable = gpio.usable_pins();
+    for pin in addresses.iter().chain(reserved.iter()){
+        if !usable.contains(pin){
+            return Err(Error::Config(format!("GPIO {} is outside the usable range {}-{}",pin,usable.start(),usable.end())));
+        }
+    }
+    let mut output:Vec<u8> = Vec::new();
+    for pin in addresses{
+        if reserved.contains(pin){
+            return Err(Error::Config(format!("GPIO {} is listed as a relay pin but is reserved",pin)));
+        }
+        if output.contains(pin){
+            return Err(Error::Config(format!("GPIO {} is listed more than once",pin)));
+        }
+        output.push(*pin);
+    }
+    if output.is_empty(){
+        return Err(Error::Config("No relay pins configured".to_string()));
+    }
+    return Ok(output);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn relay_pins_are_checked_against_the_backend(){
+        let gpio = MockGpio::new();
+        assert_eq!(validate_pins(&gpio,&[4,17,27],&[2]).unwrap(),vec![4,17,27]);
+        assert!(matches!(validate_pins(&gpio,&[4,28],&[]),Err(Error::Config(message)) if message.contains("outside the usable range")));
+        assert!(matches!(validate_pins(&gpio,&[4],&[1]),Err(Error::Config(message)) if message.contains("outside the usable range")));
+        assert!(matches!(validate_pins(&gpio,&[4,17,4],&[]),Err(Error::Config(message)) if message.contains("more than once")));
+        assert!(matches!(validate_pins(&gpio,&[4,2],&[2]),Err(Error::Config(message)) if message.contains("reserved")));
+        assert!(matches!(validate_pins(&gpio,&[],&[]),Err(Error::Config(_))));
+    }
+}
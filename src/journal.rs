@@ -0,0 +1,228 @@
+use std::{fs::{self, File, OpenOptions},
+          io::{self, Read, Seek, SeekFrom, Write},
+          time::Duration};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use crate::error::Error;
+use crate::results::results_path;
+use crate::timing::BootTiming;
+
+pub const JOURNAL_EXTENSION: &str = "journal.jsonl";
+
+#[derive(Clone,PartialEq,Debug,Serialize,Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event{
+    //Totals carried over from before the journal existed, such as a migrated results file
+    Baseline{ reboots: u64, bps: u64, temps: u64 },
+    RebootStarted,
+    RebootFinished{ counted: bool },
+    RebootFailed{ error: String },
+    //How far into a reboot the unit reached each point, up to its debug menu loading
+    BootTimed(BootTiming),
+    BpStarted,
+    //`outcome` and `polls` are missing from journals written before they were recorded
+    BpFinished{
+        counted: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        outcome: Option<BpOutcome>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        polls: Option<u64>,
+    },
+    //`registered` is whether the unit's pull counter went up for this pull, missing from
+    //journals written before it was checked
+    TempPull{
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        registered: Option<bool>,
+    },
+    //Several pulls in a row haven't registered, so the relay or the probe well is likely faulty
+    PullFault{ missed: u64 },
+    //The unit's own pull counter was read; `pulls` is how many it gained since the last read
+    TempCountUpdated{ count: u64, pulls: u64 },
+    NavigationFailed{ menu: String, error: String },
+    //The unit was found at a U-Boot prompt and booted
+    #[serde(rename = "uboot_booted")]
+    UBootBooted,
+    //Testing was stopped by a signal
+    Stopped{ returned_to_shell: bool },
+    //The supervisor saw no progress for too long and interrupted the unit
+    Stalled,
+    //`step` is the recovery step that brought a stalled unit back
+    Recovered{ step: String },
+    //Recovery failed, so the unit is left out of the rest of the run
+    Quarantined{ error: String },
+}
+
+#[derive(Clone,Copy,PartialEq,Debug,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BpOutcome{
+    //The unit never reported the BP running
+    NeverStarted,
+    Completed,
+    //Still running when the BP timeout ran out
+    TimedOut,
+    //The unit rebooted, or dropped to U-Boot, part way through
+    AbortedByReboot,
+}
+
+impl Event{
+    //Whether the event shows the unit moving the test along, rather than failing at it
+    pub fn is_progress(&self) -> bool{
+        match self{
+            Event::RebootFailed{..} | Event::NavigationFailed{..} | Event::Stalled | Event::Quarantined{..} | Event::PullFault{..} => return false,
+            _ => return true,
+        }
+    }
+}
+
+#[derive(Clone,PartialEq,Debug,Serialize,Deserialize)]
+pub struct JournalRecord{
+    pub at: DateTime<Local>,
+    pub serial: String,
+    #[serde(flatten)]
+    pub event: Event,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Clone,Copy,Default,PartialEq,Debug)]
+pub struct Totals{
+    pub reboots: u64,
+    pub bps: u64,
+    pub temps: u64,
+}
+
+impl Totals{
+    pub fn add(&mut self, event:&Event){
+        match event{
+            Event::Baseline{reboots,bps,temps} => {
+                self.reboots += reboots;
+                self.bps += bps;
+                self.temps += temps;
+            },
+            Event::RebootFinished{counted:true} => self.reboots += 1,
+            Event::BpFinished{counted:true,..} => self.bps += 1,
+            Event::TempCountUpdated{pulls,..} => self.temps += pulls,
+            _ => {},
+        }
+    }
+}
+
+//Every test event for one unit, one JSON record per line in output/<serial>.journal.jsonl.
+//The file is only ever appended to, so a resumed run picks up where the last one left off.
+#[derive(Debug)]
+pub struct Journal{
+    serial: String,
+    path: String,
+    file: File,
+    new: bool,
+}
+
+impl Journal{
+    pub fn open(folder:&str, serial:&str) -> Result<Self,Error>{
+        let path = results_path(folder,serial,JOURNAL_EXTENSION);
+        let persistence_error = |source:io::Error| Error::Persistence{ path: path.clone(), source };
+        fs::create_dir_all(folder).map_err(persistence_error)?;
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&path).map_err(persistence_error)?;
+        let length = file.metadata().map_err(persistence_error)?.len();
+        let new = length == 0;
+        //A line cut short by a power cut is ended, so the next record doesn't run on from it
+        if !new{
+            let mut last = [0u8;1];
+            file.seek(SeekFrom::Start(length - 1)).and_then(|_| file.read_exact(&mut last)).map_err(persistence_error)?;
+            if last[0] != b'\n'{
+                file.write_all(b"\n").map_err(persistence_error)?;
+            }
+        }
+        return Ok(Self{ serial: serial.to_string(), path, file, new });
+    }
+
+    //Whether nothing had been recorded before this run
+    pub fn is_new(&self) -> bool{
+        return self.new;
+    }
+
+    //Failing to journal an event is logged rather than stopping the test
+    pub fn record(&mut self, event:Event, duration:Option<Duration>){
+        let record = JournalRecord{
+            at: Local::now(),
+            serial: self.serial.clone(),
+            event,
+            duration_ms: duration.map(|duration| duration.as_millis() as u64),
+        };
+        let mut line = match serde_json::to_string(&record){
+            Ok(line) => line,
+            Err(error) => {
+                log::warn!("Unable to encode journal record {:?}: {}",record,error);
+                return;
+            }
+        };
+        line.push('\n');
+        if let Err(error) = self.file.write_all(line.as_bytes()){
+            log::warn!("Unable to write journal {}: {}",self.path,error);
+        }
+        self.new = false;
+    }
+
+    //Records are only flushed to disk here, rather than after each one, so this is called
+    //whenever the unit's results are saved
+    pub fn sync(&mut self){
+        if let Err(error) = self.file.sync_data(){
+            log::warn!("Unable to sync journal {}: {}",self.path,error);
+        }
+    }
+
+    //Reads back every record in a journal. A line cut short by a power cut is skipped.
+    pub fn read(folder:&str, serial:&str) -> Result<Vec<JournalRecord>,Error>{
+        let path = results_path(folder,serial,JOURNAL_EXTENSION);
+        let contents = fs::read_to_string(&path).map_err(|source| Error::Persistence{ path: path.clone(), source })?;
+        let mut records = Vec::new();
+        for (number,line) in contents.lines().enumerate(){
+            if line.trim().is_empty() { continue; }
+            match serde_json::from_str::<JournalRecord>(line){
+                Ok(record) => records.push(record),
+                Err(error) => log::warn!("Skipping line {} of {}: {}",number + 1,path,error),
+            }
+        }
+        return Ok(records);
+    }
+
+    //Rebuilds a unit's cumulative totals from its journal
+    pub fn totals(folder:&str, serial:&str) -> Result<Totals,Error>{
+        let mut totals = Totals::default();
+        for record in Self::read(folder,serial)?{
+            totals.add(&record.event);
+        }
+        return Ok(totals);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn totals_add_up_counted_events_and_skip_cut_off_lines(){
+        let folder = std::env::temp_dir().join(format!("seymour_journal_{}",std::process::id()));
+        _ = fs::remove_dir_all(&folder);
+        let folder = folder.to_string_lossy().to_string();
+        let mut journal = Journal::open(&folder,"unit").unwrap();
+        assert!(journal.is_new());
+        journal.record(Event::Baseline{ reboots: 10, bps: 20, temps: 30 },None);
+        journal.record(Event::RebootFinished{ counted: true },None);
+        journal.record(Event::RebootFinished{ counted: false },None);
+        journal.record(Event::BpFinished{ counted: true, outcome: Some(BpOutcome::Completed), polls: Some(3) },None);
+        journal.record(Event::BpFinished{ counted: false, outcome: Some(BpOutcome::TimedOut), polls: Some(9) },None);
+        journal.record(Event::TempCountUpdated{ count: 45, pulls: 2 },None);
+        journal.sync();
+        let mut file = OpenOptions::new().append(true).open(results_path(&folder,"unit",JOURNAL_EXTENSION)).unwrap();
+        file.write_all(b"{\"at\":\"2026-").unwrap();
+        assert_eq!(Journal::totals(&folder,"unit").unwrap(),Totals{ reboots: 11, bps: 21, temps: 32 });
+
+        //The first record after a restart isn't lost to the cut-off line before it
+        let mut journal = Journal::open(&folder,"unit").unwrap();
+        assert!(!journal.is_new());
+        journal.record(Event::RebootFinished{ counted: true },None);
+        assert_eq!(Journal::totals(&folder,"unit").unwrap(),Totals{ reboots: 12, bps: 21, temps: 32 });
+        _ = fs::remove_dir_all(&folder);
+    }
+}
@@ -1,3 +1,17 @@
 pub mod gpio_facade;
 pub mod tty;
+pub mod transport;
+pub mod simulator;
+pub mod replay;
+pub mod transcript;
 pub mod device;
+pub mod error;
+pub mod relay_map;
+pub mod results;
+pub mod journal;
+pub mod report;
+pub mod config;
+pub mod shutdown;
+pub mod profile;
+pub mod supervisor;
+pub mod timing;
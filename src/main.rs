@@ -1,12 +1,27 @@
-use seymour_life::{device::Device, 
+use seymour_life::{device::{self,Device,State}, 
                    tty::{self,TTY,Response},
-                   gpio_facade::GpioPins};
+                   gpio_facade::{self,GpioPins,GpioBackend,RppalGpio,CdevGpio,MockGpio},
+                   error::Error,
+                   simulator::{self,Simulator,SimulatorConfig},
+                   transport::MemoryTransport,
+                   replay::{Capture,ReplayTransport},
+                   transcript::Transcript,
+                   relay_map::{self,RelayMap},
+                   report::Report,
+                   results::Targets,
+                   config::Config,
+                   profile::{Profile,Profiles},
+                   supervisor::{self,Heartbeat,Supervisor},
+                   shutdown};
 use std::{io::{stdin,stdout,Write},
           thread::{self, JoinHandle},
+          sync::{Arc, atomic::Ordering},
+          time::Duration,
           path::Path,
+          process::ExitCode,
           fs};
 use chrono::{DateTime,Local};
-use clap::Parser;
+use clap::{Parser,Subcommand,ValueEnum};
 
 #[derive(Parser,Debug)]
 #[command(author,version,about)]
@@ -21,12 +36,132 @@ struct Args{
 
     /// Set iteration count from command line. Overrides debug iteration count.
     #[arg(short,long)]
-    iterations:Option<u64>
+    iterations:Option<u64>,
 
+    /// Serial port to test instead of scanning for connected devices. May be repeated.
+    #[arg(short,long)]
+    port:Vec<String>,
+
+    /// Record every byte sent to and from each device in transcripts/<serial>.transcript
+    #[arg(short,long,action)]
+    transcripts:bool,
+
+    /// Size in megabytes at which a transcript is rotated
+    #[arg(long,default_value_t=10)]
+    transcript_size:u64,
+
+    /// Number of rotated transcripts to keep per device
+    #[arg(long,default_value_t=5)]
+    transcript_keep:u32,
+
+    /// BCM GPIO pins wired to probe well relays, comma separated [default: 4,5,6,12,13,17,18,19,20,26]
+    #[arg(long,value_delimiter=',')]
+    pins:Vec<u8>,
+
+    /// BCM GPIO pins that must never be driven on this bench, comma separated
+    #[arg(long,value_delimiter=',')]
+    reserved_pins:Vec<u8>,
+
+    /// How relays are driven. cdev uses the kernel GPIO character device, and doesn't need root.
+    #[arg(long,value_enum,default_value_t=GpioDriver::Rppal)]
+    gpio:GpioDriver,
+
+    /// GPIO character device to use with --gpio cdev
+    #[arg(long,default_value_t=gpio_facade::DEFAULT_GPIO_CHIP.to_string())]
+    gpio_chip:String,
+
+    /// Run against this many simulated units and relays instead of real hardware
+    #[arg(long)]
+    simulate:Option<u32>,
+
+    /// Total reboots to test each unit up to, saved with its results. Later runs carry on toward it.
+    #[arg(long)]
+    target_reboots:Option<u64>,
+
+    /// Total BPs to test each unit up to, saved with its results. Later runs carry on toward it.
+    #[arg(long)]
+    target_bps:Option<u64>,
+
+    /// TOML file giving units their own cycle count, BPs per reboot, temp pull time and rebooting
+    #[arg(long)]
+    profiles:Option<String>,
+
+    /// Seconds a unit can go without making progress before it is interrupted and recovered
+    #[arg(long)]
+    stall_timeout:Option<u64>,
+
+    /// Seconds a recovery step can take before the unit is quarantined
+    #[arg(long)]
+    recovery_timeout:Option<u64>,
+
+    /// When stopped by Ctrl-C or SIGTERM, log each unit back in at the shell before exiting
+    #[arg(long,action)]
+    shell_on_exit:bool,
+
+    /// Run without prompting, taking iterations, serials, pins and logging from a TOML file
+    #[arg(long)]
+    config:Option<String>,
+
+    #[command(subcommand)]
+    mode:Option<Mode>,
+}
+
+#[derive(ValueEnum,Clone,Copy,Debug)]
+enum GpioDriver{
+    Rppal,
+    Cdev,
+}
+
+#[derive(Subcommand,Debug)]
+enum Mode{
+    /// Replay a captured log through the response parser, printing each response and state change
+    Replay{
+        /// Log file to replay
+        file:String,
+
+        /// Port to replay, when the log contains more than one. Any unique part of the name will do.
+        #[arg(long)]
+        port:Option<String>,
+
+        /// Playback speed multiplier
+        #[arg(long,default_value_t=1.0)]
+        speed:f64,
+
+        /// Longest pause between reads, in seconds
+        #[arg(long,default_value_t=5.0)]
+        max_gap:f64,
+    },
+    /// Summarise every unit's results as CSV, Markdown and HTML
+    Report{
+        /// Folder holding the results to summarise
+        #[arg(long,default_value_t=device::OUTPUT_FOLDER.to_string())]
+        results:String,
+
+        /// Folder to write report.csv, report.md and report.html into
+        #[arg(long,default_value_t=REPORT_FOLDER.to_string())]
+        output:String,
+
+        /// Reboots each unit should have reached, instead of the target saved with it
+        #[arg(long)]
+        target_reboots:Option<u64>,
+
+        /// BPs each unit should have reached, instead of the target saved with it
+        #[arg(long)]
+        target_bps:Option<u64>,
+    },
 }
 
 const VERSION:&str="2.3.3";
+const TRANSCRIPT_FOLDER:&str="transcripts";
+const REPORT_FOLDER:&str="reports";
+const SIMULATED_SERIAL:&str="SIM00001";
 const DEBUG_ITERATION_COUNT:u64=50000;
+//Exit codes, so whatever started the run can tell why it stopped
+const EXIT_FAILED:u8=1;
+const EXIT_BAD_CONFIG:u8=2;
+const EXIT_NO_DEVICES:u8=3;
+//A unit running toward a target has no set end, so one that keeps failing is given up on
+const MAX_CONSECUTIVE_FAILURES:u32=10;
 
 fn int_input_filtering(prompt:Option<&str>) -> u64{
     let internal_prompt = prompt.unwrap_or(">>>");
@@ -48,7 +183,7 @@ fn input_filtering(prompt:Option<&str>) -> String{
     let mut user_input:String = String::new();
     print!("{}",internal_prompt);
     _ = stdout().flush();
-    stdin().read_line(&mut user_input).ok().expect("Did not enter a correct string");
+    stdin().read_line(&mut user_input).expect("Did not enter a correct string");
     if let Some('\n')=user_input.chars().next_back() {
         user_input.pop();
     }
@@ -59,98 +194,224 @@ fn input_filtering(prompt:Option<&str>) -> String{
     return user_input;
 }
 //Path::new(&&str).is_dir() -> bool
-fn main(){
-    let args = Args::parse();
-    setup_logs(&args.debug);
+fn main() -> ExitCode{
+    let mut args = Args::parse();
+    //With a config file, nothing is read from stdin
+    let config = match args.config.as_deref().map(Config::load){
+        Some(Ok(config)) => Some(config),
+        Some(Err(error)) => {
+            eprintln!("{}",error);
+            return ExitCode::from(EXIT_BAD_CONFIG);
+        },
+        None => None,
+    };
+    let interactive = config.is_none();
+    let logging = config.as_ref().map(|config| config.logging.clone()).unwrap_or_default();
+    args.debug |= logging.debug;
+    if let Err(error) = setup_logs(&args.debug,&logging.folder,logging.stdout){
+        eprintln!("Unable to set up logging: {}",error);
+        return ExitCode::from(EXIT_BAD_CONFIG);
+    }
     log::info!("Seymour Life Testing version: {}",VERSION);
     log::trace!("Debug enabled!");
+    match args.mode{
+        Some(Mode::Replay{file,port,speed,max_gap}) => return replay(&file,port.as_deref(),speed,max_gap),
+        Some(Mode::Report{results,output,target_reboots,target_bps}) => return report(&results,&output,Targets{ reboots: target_reboots, bps: target_bps }),
+        None => {},
+    }
+    let configured_iterations = args.iterations.or(config.as_ref().and_then(|config| config.iterations));
+    let targets = Targets{
+        reboots: args.target_reboots.or(config.as_ref().and_then(|config| config.target_reboots)),
+        bps: args.target_bps.or(config.as_ref().and_then(|config| config.target_bps)),
+    };
+    let profiles = match args.profiles.clone().or(config.as_ref().and_then(|config| config.profiles.clone())){
+        Some(path) => match Profiles::load(&path){
+            Ok(profiles) => profiles,
+            Err(error) => {
+                log::error!("{}",error);
+                return ExitCode::from(EXIT_BAD_CONFIG);
+            }
+        },
+        None => Profiles::default(),
+    };
+    if args.pins.is_empty(){
+        args.pins = config.as_ref().and_then(|config| config.pins.clone()).unwrap_or(gpio_facade::DEFAULT_RELAY_ADDRESSES.to_vec());
+    }
+    if args.reserved_pins.is_empty(){
+        args.reserved_pins = config.as_ref().and_then(|config| config.reserved_pins.clone()).unwrap_or_default();
+    }
+    args.shell_on_exit |= config.as_ref().is_some_and(|config| config.shell_on_exit);
+    let stall_timeout = match args.stall_timeout.or(config.as_ref().and_then(|config| config.stall_timeout)){
+        Some(0) => {
+            log::error!("The stall timeout must be at least 1 second");
+            return ExitCode::from(EXIT_BAD_CONFIG);
+        },
+        Some(seconds) => Duration::from_secs(seconds),
+        None => supervisor::DEFAULT_STALL_TIMEOUT,
+    };
+    let recovery_timeout = match args.recovery_timeout.or(config.as_ref().and_then(|config| config.recovery_timeout)){
+        Some(0) => {
+            log::error!("The recovery timeout must be at least 1 second");
+            return ExitCode::from(EXIT_BAD_CONFIG);
+        },
+        Some(seconds) => Duration::from_secs(seconds),
+        None => supervisor::DEFAULT_RECOVERY_TIMEOUT,
+    };
+    if let Err(error) = shutdown::install_handler(){
+        log::warn!("Unable to handle Ctrl-C, stopping will leave relays as they are: {}",error);
+    }
+    let simulation = match args.simulate{
+        Some(_) => match config.as_ref().map(|config| config.simulate.clone()).unwrap_or_default().simulator_config(){
+            Ok(simulation) => Some(simulation),
+            Err(error) => {
+                log::error!("{}",error);
+                return ExitCode::from(EXIT_BAD_CONFIG);
+            }
+        },
+        None => None,
+    };
+    if args.simulate.is_some() && !args.port.is_empty(){
+        log::warn!("Ports given with --port are not tested when simulating");
+    }
+    let mut run_failed = false;
+    let mock_gpio = args.simulate.map(|_| MockGpio::new());
+    let possible_backend:Result<Arc<dyn GpioBackend>,Error> = match (&mock_gpio,args.gpio){
+        (Some(mock),_) => Ok(Arc::new(mock.clone())),
+        (None,GpioDriver::Rppal) => RppalGpio::new().map(|gpio| Arc::new(gpio) as Arc<dyn GpioBackend>),
+        (None,GpioDriver::Cdev) => CdevGpio::new(&args.gpio_chip).map(|gpio| Arc::new(gpio) as Arc<dyn GpioBackend>),
+    };
+    let gpio_backend = match possible_backend{
+        Ok(gpio) => gpio,
+        Err(error) => {
+            log::error!("Unable to set up relays: {}",error);
+            return ExitCode::from(EXIT_FAILED);
+        }
+    };
+    let relay_pins = match gpio_facade::validate_pins(gpio_backend.as_ref(),&args.pins,&args.reserved_pins){
+        Ok(pins) => pins,
+        Err(error) => {
+            log::error!("{}",error);
+            return ExitCode::from(EXIT_BAD_CONFIG);
+        }
+    };
+    log::debug!("Relay pins: {:?}, reserved pins: {:?}",relay_pins,args.reserved_pins);
     loop{
         let mut iteration_count:u64 = 0;
-        if let Some(value) = args.iterations{
+        if let Some(value) = configured_iterations{
             iteration_count = value;
         }
         else if args.debug { 
             iteration_count = DEBUG_ITERATION_COUNT;
         }
-        else {
-            while iteration_count < 1{
-                iteration_count = int_input_filtering(Some("Enter the number of iterations to complete: "));
-            }
-        }
 
         log::info!("Testing all available USB ports for connected devices. This may take several minutes, and devices may reboot several times.");
-        let gpio = &mut GpioPins::new();
-        let mut available_ttys:Vec<Box<Path>> = Vec::new();
-        for entry in glob::glob("/dev/serial/*").expect("Failed to read glob pattern"){
-            match entry{
-                Ok(real_path) =>{
-                    match fs::read_dir::<&Path>(real_path.as_ref()){
-                        Ok(possible_ttys) =>{
-                            possible_ttys.into_iter().for_each(|tty| {
-                                if let Ok(single_tty) = tty {
-                                    available_ttys.push(single_tty.path().into());
+        let gpio = &mut GpioPins::new(gpio_backend.as_ref(),&relay_pins);
+        let mut ports:Vec<TTY> = Vec::new();
+        if let (Some(count), Some(mock), Some(simulation)) = (args.simulate, &mock_gpio, &simulation){
+            ports = simulate_units(count,simulation,mock,&relay_pins);
+        }
+        else {
+            let mut available_ttys:Vec<Box<Path>> = args.port.iter().map(|port| Path::new(port).into()).collect();
+            if available_ttys.is_empty(){
+                for entry in glob::glob("/dev/serial/*").expect("Failed to read glob pattern"){
+                    match entry{
+                        Ok(real_path) =>{
+                            match fs::read_dir::<&Path>(real_path.as_ref()){
+                                Ok(possible_ttys) =>{
+                                    possible_ttys.into_iter().for_each(|tty| {
+                                        if let Ok(single_tty) = tty {
+                                            available_ttys.push(single_tty.path().into());
+                                        }
+                                    });
+                                    break;
                                 }
-                            });
-                            break;
+                                Err(error) =>{
+                                    log::error!("Invalid permissions to /dev directory... did you run with sudo?");
+                                    log::error!("{}",error);
+                                    return ExitCode::from(EXIT_FAILED);
+                                }
+                            }
                         }
                         Err(error) =>{
-                            log::error!("Invalid permissions to /dev directory... did you run with sudo?");
                             log::error!("{}",error);
-                            return;
                         }
                     }
                 }
-                Err(error) =>{
-                    log::error!("{}",error);
+            }
+            if available_ttys.is_empty(){
+                for entry in glob::glob("/dev/ttyUSB*").expect("Unable to read glob"){
+                    match entry{
+                        Ok(possible_tty) => available_ttys.push(Path::new(&possible_tty).into()),
+                        Err(error) => {
+                            log::error!("Invalid permissions to /dev directory... did you run with sudo?");
+                            log::error!("{}",error);
+                            return ExitCode::from(EXIT_FAILED);
+                        }
+                    };
                 }
             }
-        }
-        if available_ttys.is_empty(){
-            for entry in glob::glob("/dev/ttyUSB*").expect("Unable to read glob"){
-                match entry{
-                    Ok(possible_tty) => available_ttys.push(Path::new(&possible_tty).into()),
-                    Err(error) => {
-                        log::error!("Invalid permissions to /dev directory... did you run with sudo?");
-                        log::error!("{}",error);
-                        return;
-                    }
-                };
+            for possible_tty in available_ttys.into_iter(){
+                let tty_name = possible_tty.to_string_lossy();
+                match TTY::new(&tty_name){
+                    Ok(port) => ports.push(port),
+                    Err(error) => log::debug!("Unable to open {}: {}",tty_name,error),
+                }
             }
         }
 
-        if available_ttys.is_empty(){
+        if ports.is_empty(){
             log::error!("No serial devices detected! Please ensure all connections.");
-            return;
+            return ExitCode::from(EXIT_NO_DEVICES);
         }
         let mut possible_devices:Vec<Option<Device>> = Vec::new();
         let mut tty_test_threads:Vec<JoinHandle<Option<Device>>> = Vec::new();
-        for possible_tty in available_ttys.into_iter(){
+        for mut port in ports.into_iter(){
+            let gpio_backend = gpio_backend.clone();
+            let serial_override = config.as_ref().and_then(|config| config.serial_for(&port.port_name())).map(str::to_string);
             tty_test_threads.push(
                 thread::spawn(move ||{
-                    let tty_name = possible_tty.to_string_lossy();
+                    let tty_name = port.port_name();
                     log::debug!("Testing port {}",&tty_name);
-                    let possible_port = TTY::new(&tty_name);
-                    match possible_port{
-                        Some(mut port) =>{
-                            port.write_to_device(tty::Command::Newline);
-                            let response = port.read_from_device(Some(":"));
-                            if response != Response::Empty{
-                                log::debug!("{} is valid port!",tty_name);
-                                let new_device = Device::new(port,Some(response));
-                                match new_device{
-                                    Ok(mut device) => {
-                                        device.darken_screen();
-                                        if !args.manual {
-                                            device.auto_set_serial();
+                    if args.transcripts{
+                        match Transcript::new(TRANSCRIPT_FOLDER,&tty_name,args.transcript_size * 1024 * 1024,args.transcript_keep){
+                            Ok(transcript) => port.start_transcript(transcript),
+                            Err(error) => log::warn!("Unable to start transcript for {}: {}",tty_name,error),
+                        }
+                    }
+                    let response = port.write_to_device(tty::Command::Newline)
+                        .and_then(|_| port.read_from_device(Some(":")));
+                    match response{
+                        Ok(Response::Empty) => None,
+                        Ok(response) => {
+                            log::debug!("{} is valid port!",tty_name);
+                            let new_device = Device::new(port,Some(response),gpio_backend);
+                            match new_device{
+                                Ok(mut device) => {
+                                    if let Err(error) = device.darken_screen(){
+                                        log::warn!("Unable to darken screen on {}: {}",tty_name,error);
+                                    }
+                                    if let Some(serial) = serial_override{
+                                        if let Err(error) = device.manual_set_serial(&serial){
+                                            log::warn!("Unable to save values for device {}: {}",serial,error);
+                                        }
+                                    }
+                                    else if !args.manual {
+                                        if let Err(error) = device.auto_set_serial(){
+                                            log::warn!("Unable to read serial from {}: {}",tty_name,error);
                                         }
-                                        Some(device)
-                                    },
-                                    Err(_) => None
+                                    }
+                                    Some(device)
+                                },
+                                Err(error) => {
+                                    log::debug!("Unable to set up device on {}: {}",tty_name,error);
+                                    None
                                 }
                             }
-                            else { None }
                         },
-                        None=>{None}
+                        Err(error) => {
+                            log::debug!("Unable to talk to {}: {}",tty_name,error);
+                            None
+                        }
                     }
             }));
         }
@@ -161,13 +422,25 @@ fn main(){
 
         let mut serials_set:bool = true;
         let mut devices:Vec<Device> = Vec::new();
-        for possible_device in possible_devices.into_iter(){
-            if let Some(device) = possible_device{
-                if device.get_serial().eq("uninitialised"){
-                    serials_set = false;
-                }
-                devices.push(device);
+        for device in possible_devices.into_iter().flatten(){
+            if device.get_serial().eq(device::UNINITIALISED_SERIAL){
+                serials_set = false;
             }
+            devices.push(device);
+        }
+
+        if !interactive && !serials_set{
+            devices.retain(|device|{
+                if device.get_serial() != device::UNINITIALISED_SERIAL { return true; }
+                log::error!("Unable to read the serial of the device on {}. Add it to [serials] in the config file.",device.get_port());
+                return false;
+            });
+            run_failed = true;
+            serials_set = true;
+        }
+        if devices.is_empty(){
+            log::error!("No devices could be set up.");
+            return ExitCode::from(EXIT_NO_DEVICES);
         }
 
         log::info!("--------------------------------------");
@@ -176,61 +449,316 @@ fn main(){
 
         log::info!("Setting up probe wells for all devices. This may take several minutes...");
         for device in devices.iter_mut(){
-            if !serials_set || args.manual {
-            device.brighten_screen();
-            device.manual_set_serial(&input_filtering(Some("Enter the serial of the device with the bright screen: ")).to_string());
-            device.darken_screen();
+            if interactive && (!serials_set || args.manual) {
+            if let Err(error) = device.brighten_screen(){
+                log::warn!("Unable to brighten screen on {}: {}",device.get_location(),error);
+            }
+            if let Err(error) = device.manual_set_serial(&input_filtering(Some("Enter the serial of the device with the bright screen: ")).to_string()){
+                log::warn!("Unable to save values for device {}: {}",device.get_serial(),error);
+            }
+            if let Err(error) = device.darken_screen(){
+                log::warn!("Unable to darken screen on device {}: {}",device.get_serial(),error);
+            }
+            }
+        }
+
+        //Units whose relay is already known only need a single pull to confirm it
+        let relay_map_path = Path::new(device::OUTPUT_FOLDER).join(relay_map::RELAY_MAP_FILE_NAME).to_string_lossy().to_string();
+        let mut relay_map = RelayMap::load(&relay_map_path);
+        let mut unverified:Vec<&mut Device> = Vec::new();
+        for device in devices.iter_mut(){
+            let Some(assignment) = relay_map.get(device.get_serial()).cloned() else {
+                unverified.push(device);
+                continue;
+            };
+            if assignment.port != device.get_port(){
+                log::info!("Device {} has moved from {} to {}",device.get_serial(),assignment.port,device.get_port());
             }
+            log::info!("Verifying probe well of device {} on pin {}",device.get_serial(),assignment.pin);
+            if verify_gpio(device,gpio,assignment.pin){
+                relay_map.insert(device.get_serial(),assignment.pin,&device.get_port());
+            }
+            else {
+                log::warn!("Device {} is no longer on pin {}, searching all probe wells",device.get_serial(),assignment.pin);
+                relay_map.remove(device.get_serial());
+                unverified.push(device);
+            }
+        }
+        for device in unverified{
             log::info!("Checking probe well of device {}",device.get_serial());
             log::debug!("Number of unassigned addresses: {}",gpio.get_unassigned_addresses().len());
-            if !find_gpio(device, gpio){
-                device.set_pin_address(21);
-                log::error!("Unable to find probe-well for device {}. Please ensure that the probe well is installed properly, and the calibration key is plugged in.",device.get_serial());
-                device.brighten_screen();
-                panic!();
+            match find_gpio(device, gpio){
+                Some(address) => relay_map.insert(device.get_serial(),address,&device.get_port()),
+                None => {
+                    log::error!("Unable to find probe-well for device {}. Please ensure that the probe well is installed properly, and the calibration key is plugged in.",device.get_serial());
+                    _ = device.brighten_screen();
+                    return ExitCode::from(EXIT_FAILED);
+                }
+            }
+        }
+        if let Err(error) = relay_map.save(&relay_map_path){
+            log::warn!("Unable to save relay map: {}",error);
+        }
+
+        if targets.is_set(){
+            for device in devices.iter_mut(){
+                if let Err(error) = device.set_target(targets){
+                    log::warn!("Unable to save target for device {}: {}",device.get_serial(),error);
+                }
+            }
+        }
+        for device in devices.iter_mut(){
+            let profile = profiles.for_serial(device.get_serial());
+            if profile != Profile::default(){
+                log::info!("Device {} is using profile {:?}",device.get_serial(),profile);
+            }
+            device.set_profile(profile);
+        }
+        //Units with a target carry on toward it, so only units without one or a cycle count of
+        //their own need to be told how many iterations to run
+        let uncounted:Vec<&str> = devices.iter()
+            .filter(|device| !device.get_target().is_set() && device.get_profile().cycles.is_none())
+            .map(|device| device.get_serial())
+            .collect();
+        if iteration_count < 1 && !uncounted.is_empty(){
+            if !interactive{
+                log::error!("No iteration count or target for devices {:?}. Set iterations or a target in the config file, or cycles in their profiles.",uncounted);
+                return ExitCode::from(EXIT_BAD_CONFIG);
+            }
+            while iteration_count < 1{
+                //Stdin may be closed, in which case this would never be answered
+                if shutdown::requested() { return ExitCode::SUCCESS; }
+                iteration_count = int_input_filtering(Some("Enter the number of iterations to complete: "));
             }
         }
 
-        let mut iteration_threads = Vec::new();
+        let mut supervisor = Supervisor::new(stall_timeout,recovery_timeout);
         while let Some(mut device) = devices.pop(){
-            iteration_threads.push(thread::spawn(move||{
-                device.init_temp_count();
-                for i in 1..=iteration_count{
-                    log::info!("Starting iteration {} of {} for device {}...",
-                                   i,iteration_count,device.get_serial());
-                    device.test_cycle(None);
+            let heartbeat = Heartbeat::new();
+            device.supervise(heartbeat.clone());
+            let serial = device.get_serial().to_string();
+            let iteration_thread = thread::spawn(move||{
+                let target = device.get_target();
+                let cycles = device.get_profile().cycles.unwrap_or(iteration_count);
+                if device.target_reached(){
+                    log::info!("Device {} has already reached its target of {}, skipping",device.get_serial(),target);
+                    return true;
                 }
-            }));
+                if target.reboots.is_some() && !device.get_profile().reboot_every_cycle{
+                    log::error!("Device {} has a reboot target, but its profile never reboots it",device.get_serial());
+                    return false;
+                }
+                if target.bps.is_some() && device.get_profile().bps_per_reboot == 0{
+                    log::error!("Device {} has a BP target, but its profile runs no BPs",device.get_serial());
+                    return false;
+                }
+                if let Err(error) = device.init_temp_count(){
+                    log::error!("Unable to read initial temp count for device {}: {}",device.get_serial(),error);
+                }
+                let mut i:u64 = 0;
+                let mut consecutive_failures:u32 = 0;
+                loop{
+                    if shutdown::requested() { break; }
+                    if target.is_set(){
+                        if device.target_reached(){
+                            log::info!("Device {} has reached its target of {}",device.get_serial(),target);
+                            break;
+                        }
+                        if consecutive_failures >= MAX_CONSECUTIVE_FAILURES{
+                            log::error!("Giving up on device {} after {} failed iterations in a row",device.get_serial(),consecutive_failures);
+                            device.finish_run();
+                            return false;
+                        }
+                    }
+                    else if i >= cycles { break; }
+                    i += 1;
+                    if target.is_set(){
+                        log::info!("Starting iteration {} for device {} ({} reboots and {} BPs so far, target {})...",
+                                       i,device.get_serial(),device.get_reboots(),device.get_bps(),target);
+                    }
+                    else {
+                        log::info!("Starting iteration {} of {} for device {}...",
+                                       i,cycles,device.get_serial());
+                    }
+                    let bps_before = device.get_bps();
+                    match device.test_cycle(None){
+                        //A unit whose BPs all time out or never start would otherwise cycle forever
+                        Ok(()) if target.bps.is_some() && device.get_bps() == bps_before && !shutdown::requested() => {
+                            log::error!("Iteration {} on device {} completed no BPs toward its target",i,device.get_serial());
+                            consecutive_failures += 1;
+                        },
+                        Ok(()) => consecutive_failures = 0,
+                        Err(error) => {
+                            log::error!("Iteration {} failed on device {}: {}",i,device.get_serial(),error);
+                            consecutive_failures += 1;
+                        }
+                    }
+                    if device.is_stalled(){
+                        if let Err(error) = device.recover(){
+                            log::error!("Unable to recover device {}, leaving it out of the rest of the run: {}",device.get_serial(),error);
+                            device.finish_run();
+                            return false;
+                        }
+                    }
+                    //The supervisor stopped waiting for this unit while it was stuck
+                    if device.is_quarantined(){
+                        device.finish_run();
+                        return false;
+                    }
+                }
+                device.finish_run();
+                if shutdown::requested(){
+                    if let Err(error) = device.stop(args.shell_on_exit){
+                        log::error!("Unable to save results for device {} while stopping: {}",device.get_serial(),error);
+                        return false;
+                    }
+                    log::info!("Device {} stopped",device.get_serial());
+                }
+                return true;
+            });
+            supervisor.watch(&serial,heartbeat,iteration_thread);
         }
-        for thread in iteration_threads{
-            thread.join().unwrap();
+        if !supervisor.wait(){
+            run_failed = true;
         }
+        if !interactive || shutdown::requested() { break; }
         if input_filtering(Some("Would you like to run the tests again? (y/N): ")).to_string().contains("y") {}
         else { break; }
     }
+    if run_failed{
+        return ExitCode::from(EXIT_FAILED);
+    }
+    if shutdown::requested(){
+        log::info!("Testing stopped early");
+    }
+    return ExitCode::SUCCESS;
+}
+
+fn replay(file:&str,port:Option<&str>,speed:f64,max_gap:f64) -> ExitCode{
+    let capture = match Capture::load(file,port){
+        Ok(capture) => capture,
+        Err(error) => {
+            log::error!("{}",error);
+            return ExitCode::from(EXIT_FAILED);
+        }
+    };
+    let name = capture.ports.first().cloned().unwrap_or(file.to_string());
+    log::info!("Replaying {} chunks from {}",capture.chunks.len(),name);
+    let transport = ReplayTransport::new(&name,capture,speed,Duration::from_secs_f64(max_gap.max(0.0)));
+    let finished = transport.finished();
+    let mut tty = TTY::from_transport(Box::new(transport));
+    let mut state = State::LoginPrompt;
+    let start = std::time::Instant::now();
+    loop{
+        let response = match tty.read_from_device(None){
+            Ok(response) => response,
+            Err(error) => {
+                log::error!("{}",error);
+                return ExitCode::from(EXIT_FAILED);
+            }
+        };
+        if response == Response::Empty {
+            if finished.load(Ordering::SeqCst) { break; }
+            continue;
+        }
+        println!("{:>9.3}s  {:?}",start.elapsed().as_secs_f64(),response);
+        if let Some(next_state) = state.after_response(&response){
+            if next_state != state{
+                println!("{:>9.3}s    state: {:?} -> {:?}",start.elapsed().as_secs_f64(),state,next_state);
+                state = next_state;
+            }
+        }
+    }
+    return ExitCode::SUCCESS;
+}
+
+fn report(results:&str,output:&str,targets:Targets) -> ExitCode{
+    let report = match Report::collect(results,targets){
+        Ok(report) => report,
+        Err(error) => {
+            log::error!("{}",error);
+            return ExitCode::from(EXIT_FAILED);
+        }
+    };
+    print!("{}",report.to_markdown());
+    match report.write(output){
+        Ok(paths) => log::info!("Report written to {}",paths.join(", ")),
+        Err(error) => {
+            log::error!("Unable to write report: {}",error);
+            return ExitCode::from(EXIT_FAILED);
+        }
+    }
+    return ExitCode::SUCCESS;
+}
+
+//Starts simulated units in this process, each wired to its own mock relay
+fn simulate_units(count:u32,simulation:&SimulatorConfig,gpio:&MockGpio,relay_pins:&[u8]) -> Vec<TTY>{
+    if count as usize > relay_pins.len(){
+        log::warn!("Only {} relay pins are configured, simulating {} units",relay_pins.len(),relay_pins.len());
+    }
+    let mut ports = Vec::new();
+    for (index,pin) in relay_pins.iter().take(count as usize).enumerate(){
+        let serial = simulator::nth_serial(SIMULATED_SERIAL,index as u32);
+        let (local, remote) = MemoryTransport::pair(&format!("sim/{}",serial));
+        let mut config = simulation.clone();
+        config.serial = serial.clone();
+        config.seed = simulation.seed.wrapping_add(index as u64);
+        let simulator = Simulator::new(Box::new(remote),config);
+        gpio.connect(*pin,simulator.temp_counter());
+        log::info!("Simulated unit {} wired to pin {}",serial,pin);
+        thread::spawn(move || simulator.run());
+        ports.push(TTY::from_transport(Box::new(local)));
+    }
+    return ports;
 }
 
-fn find_gpio(device:&mut Device,gpio:&mut GpioPins) -> bool{
-    device.init_temp_count();
+fn find_gpio(device:&mut Device,gpio:&mut GpioPins) -> Option<u8>{
+    if let Err(error) = device.init_temp_count(){
+        log::warn!("Unable to read initial temp count for device {}: {}",device.get_serial(),error);
+    }
     for &address in gpio.get_unassigned_addresses(){
-        device.set_pin_address(address).start_temp();
-        if device.is_temp_running(){
-            device.stop_temp();
+        if pull_probe(device,address){
             gpio.remove_address(address);
-            return true;
-        }
-        else {
-            device.stop_temp();
+            return Some(address);
         }
     }
+    return None;
+}
+
+fn verify_gpio(device:&mut Device,gpio:&mut GpioPins,address:u8) -> bool{
+    if !gpio.get_unassigned_addresses().contains(&address){
+        log::debug!("Pin {} is not available to device {}",address,device.get_serial());
+        return false;
+    }
+    if let Err(error) = device.init_temp_count(){
+        log::warn!("Unable to read initial temp count for device {}: {}",device.get_serial(),error);
+    }
+    if pull_probe(device,address){
+        gpio.remove_address(address);
+        return true;
+    }
     return false;
 }
 
-pub fn setup_logs(debug:&bool){
+//Pulls the probe through the given relay, and reports whether the device counted it
+fn pull_probe(device:&mut Device,address:u8) -> bool{
+    match device.set_pin_address(address){
+        Ok(device) => { device.start_temp(); },
+        Err(_) => return false,
+    }
+    let running = device.is_temp_running().unwrap_or(false);
+    device.stop_temp();
+    return running;
+}
+
+pub fn setup_logs(debug:&bool,folder:&str,to_stdout:bool) -> Result<(),Error>{
     let chrono_now: DateTime<Local> = Local::now();
-    if ! Path::new("logs").is_dir(){
-        _ = fs::create_dir("logs");
+    let date_format = chrono_now.format("%Y-%m-%d_%H.%M").to_string();
+    let log_path = Path::new(folder).join(format!("{}.log",date_format));
+    if ! Path::new(folder).is_dir(){
+        fs::create_dir_all(folder).map_err(|source| Error::Persistence{ path: folder.to_string(), source })?;
     };
+    let local_log_file = fern::log_file(&log_path).map_err(|source| Error::Persistence{ path: log_path.to_string_lossy().to_string(), source })?;
     _ = fern::Dispatch::new()
         .format(|out,message,record|{
             out.finish(format_args!(
@@ -243,8 +771,6 @@ pub fn setup_logs(debug:&bool){
         })
         .chain({
             let mut file_logger = fern::Dispatch::new();
-            let date_format = chrono_now.format("%Y-%m-%d_%H.%M").to_string();
-            let local_log_file = fern::log_file(format!("logs/{}.log",date_format)).unwrap();
             if *debug{
                 file_logger = file_logger.level(log::LevelFilter::Trace);
             }
@@ -260,8 +786,12 @@ pub fn setup_logs(debug:&bool){
             }
             else {
                 stdout_logger = stdout_logger.level(log::LevelFilter::Info);
+            }
+            if !to_stdout{
+                stdout_logger = stdout_logger.level(log::LevelFilter::Off);
             }
                 stdout_logger.chain(std::io::stdout())
         })
         .apply();
+    return Ok(());
 }
@@ -0,0 +1,144 @@
+use std::{collections::BTreeMap, fs, time::Duration};
+use serde::Deserialize;
+use crate::error::Error;
+
+pub const DEFAULT_BPS_PER_REBOOT: u64 = 3;
+pub const DEFAULT_TEMP_PULL_SECONDS: f64 = 3.0;
+pub const DEFAULT_MAX_BP_SECONDS: f64 = 300.0;
+pub const DEFAULT_BP_POLL_SECONDS: f64 = 1.0;
+
+//How a unit is exercised. Units in different test campaigns can share a rig by each having
+//their own profile.
+#[derive(Clone,Copy,PartialEq,Debug,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile{
+    //Iterations to run this time, in place of the count given for the whole run
+    pub cycles: Option<u64>,
+    pub bps_per_reboot: u64,
+    pub temp_pull_seconds: f64,
+    pub reboot_every_cycle: bool,
+    //A BP still running after this long is given up on, and the unit rebooted to clear it
+    pub max_bp_seconds: f64,
+    //How often a running BP is checked on
+    pub bp_poll_seconds: f64,
+}
+
+impl Default for Profile{
+    fn default() -> Self{
+        Self{
+            cycles: None,
+            bps_per_reboot: DEFAULT_BPS_PER_REBOOT,
+            temp_pull_seconds: DEFAULT_TEMP_PULL_SECONDS,
+            reboot_every_cycle: true,
+            max_bp_seconds: DEFAULT_MAX_BP_SECONDS,
+            bp_poll_seconds: DEFAULT_BP_POLL_SECONDS,
+        }
+    }
+}
+
+impl Profile{
+    pub fn temp_pull(&self) -> Duration{
+        return Duration::from_secs_f64(self.temp_pull_seconds);
+    }
+
+    pub fn max_bp(&self) -> Duration{
+        return Duration::from_secs_f64(self.max_bp_seconds);
+    }
+
+    pub fn bp_poll(&self) -> Duration{
+        return Duration::from_secs_f64(self.bp_poll_seconds);
+    }
+
+    fn validate(&self, name:&str) -> Result<(),Error>{
+        if !self.temp_pull_seconds.is_finite() || self.temp_pull_seconds < 0.0{
+            return Err(Error::Config(format!("{}: temp_pull_seconds must be zero or more",name)));
+        }
+        if !self.max_bp_seconds.is_finite() || self.max_bp_seconds <= 0.0{
+            return Err(Error::Config(format!("{}: max_bp_seconds must be more than zero",name)));
+        }
+        if !self.bp_poll_seconds.is_finite() || self.bp_poll_seconds <= 0.0{
+            return Err(Error::Config(format!("{}: bp_poll_seconds must be more than zero",name)));
+        }
+        if self.bps_per_reboot == 0 && !self.reboot_every_cycle{
+            return Err(Error::Config(format!("{}: a cycle with no BPs and no reboot does nothing",name)));
+        }
+        if self.cycles == Some(0){
+            return Err(Error::Config(format!("{}: cycles must be at least 1",name)));
+        }
+        return Ok(());
+    }
+}
+
+//A unit's own settings. Anything left out is taken from `[default]`.
+#[derive(Clone,Copy,Default,PartialEq,Debug,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnitProfile{
+    pub cycles: Option<u64>,
+    pub bps_per_reboot: Option<u64>,
+    pub temp_pull_seconds: Option<f64>,
+    pub reboot_every_cycle: Option<bool>,
+    pub max_bp_seconds: Option<f64>,
+    pub bp_poll_seconds: Option<f64>,
+}
+
+impl UnitProfile{
+    pub fn over(&self, default:&Profile) -> Profile{
+        Profile{
+            cycles: self.cycles.or(default.cycles),
+            bps_per_reboot: self.bps_per_reboot.unwrap_or(default.bps_per_reboot),
+            temp_pull_seconds: self.temp_pull_seconds.unwrap_or(default.temp_pull_seconds),
+            reboot_every_cycle: self.reboot_every_cycle.unwrap_or(default.reboot_every_cycle),
+            max_bp_seconds: self.max_bp_seconds.unwrap_or(default.max_bp_seconds),
+            bp_poll_seconds: self.bp_poll_seconds.unwrap_or(default.bp_poll_seconds),
+        }
+    }
+}
+
+//Profiles keyed by serial, read from a TOML file. Units not listed get `default`.
+#[derive(Clone,Default,PartialEq,Debug,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profiles{
+    pub default: Profile,
+    pub units: BTreeMap<String,UnitProfile>,
+}
+
+impl Profiles{
+    pub fn load(path:&str) -> Result<Self,Error>{
+        let contents = fs::read_to_string(path).map_err(|source| Error::Persistence{ path: path.to_string(), source })?;
+        let profiles = toml::from_str::<Self>(&contents).map_err(|error| Error::Config(format!("{}: {}",path,error)))?;
+        profiles.default.validate(&format!("{} [default]",path))?;
+        for serial in profiles.units.keys(){
+            profiles.for_serial(serial).validate(&format!("{} [units.{}]",path,serial))?;
+        }
+        return Ok(profiles);
+    }
+
+    pub fn for_serial(&self, serial:&str) -> Profile{
+        return self.units.get(serial).map_or(self.default,|unit| unit.over(&self.default));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn unit_profiles_inherit_from_default(){
+        let profiles = toml::from_str::<Profiles>("[default]\ncycles = 1\ntemp_pull_seconds = 2.0\n\n[units.A]\nbps_per_reboot = 1\n").unwrap();
+        let unit = profiles.for_serial("A");
+        assert_eq!(unit.cycles,Some(1));
+        assert_eq!(unit.bps_per_reboot,1);
+        assert_eq!(unit.temp_pull_seconds,2.0);
+        assert_eq!(unit.max_bp_seconds,DEFAULT_MAX_BP_SECONDS);
+        assert_eq!(profiles.for_serial("B"),profiles.default);
+    }
+
+    #[test]
+    fn merged_unit_profiles_are_validated(){
+        let path = std::env::temp_dir().join(format!("seymour_profiles_{}.toml",std::process::id()));
+        fs::write(&path,"[default]\nreboot_every_cycle = false\n\n[units.A]\nbps_per_reboot = 0\n").unwrap();
+        let result = Profiles::load(&path.to_string_lossy());
+        _ = fs::remove_file(&path);
+        assert!(matches!(result,Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,96 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+use serde::{Deserialize, Serialize};
+use crate::error::Error;
+use crate::results::write_atomic;
+
+pub const RELAY_MAP_FILE_NAME: &str = "relay_map.json";
+
+#[derive(Clone,PartialEq,Debug,Serialize,Deserialize)]
+pub struct RelayAssignment{
+    pub pin: u8,
+    pub port: String,
+}
+
+//Which relay and USB port each unit was last found on, keyed by serial. Finding a probe well
+//from scratch pulls the probe on every relay, so a known pin is checked first.
+#[derive(Default,Debug,Serialize,Deserialize)]
+pub struct RelayMap{
+    devices: BTreeMap<String,RelayAssignment>,
+}
+
+impl RelayMap{
+    //A missing or unreadable map just means every unit is discovered from scratch
+    pub fn load(path:&str) -> Self{
+        let contents = match fs::read_to_string(path){
+            Ok(contents) => contents,
+            Err(error) => {
+                if error.kind() != io::ErrorKind::NotFound{
+                    log::warn!("Unable to read relay map {}: {}",path,error);
+                }
+                return Self::default();
+            }
+        };
+        match serde_json::from_str(&contents){
+            Ok(map) => return map,
+            Err(error) => {
+                log::warn!("Relay map {} is corrupt, probe wells will be rediscovered: {}",path,error);
+                return Self::default();
+            }
+        }
+    }
+
+    pub fn save(&self, path:&str) -> Result<(),Error>{
+        let persistence_error = |source:io::Error| Error::Persistence{ path: path.to_string(), source };
+        if let Some(parent) = Path::new(path).parent(){
+            fs::create_dir_all(parent).map_err(persistence_error)?;
+        }
+        let contents = serde_json::to_string_pretty(self).map_err(|error| persistence_error(error.into()))?;
+        write_atomic(path,contents.as_bytes(),false).map_err(persistence_error)?;
+        return Ok(());
+    }
+
+    pub fn get(&self, serial:&str) -> Option<&RelayAssignment>{
+        return self.devices.get(serial);
+    }
+
+    pub fn insert(&mut self, serial:&str, pin:u8, port:&str){
+        self.devices.insert(serial.to_string(),RelayAssignment{ pin, port: port.to_string() });
+    }
+
+    pub fn remove(&mut self, serial:&str){
+        self.devices.remove(serial);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn test_path(name:&str) -> String{
+        return std::env::temp_dir().join(format!("seymour_relay_map_{}_{}.json",name,std::process::id())).to_string_lossy().to_string();
+    }
+
+    #[test]
+    fn saved_assignments_load_back(){
+        let path = test_path("round_trip");
+        let mut map = RelayMap::default();
+        map.insert("A",4,"/dev/ttyUSB0");
+        map.insert("B",17,"/dev/ttyUSB1");
+        map.remove("B");
+        map.save(&path).unwrap();
+        let loaded = RelayMap::load(&path);
+        _ = fs::remove_file(&path);
+        assert_eq!(loaded.get("A"),Some(&RelayAssignment{pin:4,port:"/dev/ttyUSB0".to_string()}));
+        assert_eq!(loaded.get("B"),None);
+    }
+
+    #[test]
+    fn corrupt_map_loads_empty(){
+        let path = test_path("corrupt");
+        fs::write(&path,"{\"devices\":{\"A\":{\"pin\":4,").unwrap();
+        let loaded = RelayMap::load(&path);
+        _ = fs::remove_file(&path);
+        assert_eq!(loaded.get("A"),None);
+        assert!(RelayMap::load(&test_path("missing")).get("A").is_none());
+    }
+}
@@ -0,0 +1,296 @@
+use std::{fs,
+          io::{self, ErrorKind},
+          sync::{Arc, atomic::{AtomicBool, Ordering}},
+          thread,
+          time::{Duration, Instant}};
+use chrono::{DateTime, FixedOffset};
+use crate::transport::Transport;
+use crate::transcript::READ_MARKER;
+
+//115200 baud with 8N1 framing is roughly 11.5 bytes per millisecond
+const BYTES_PER_MILLISECOND: usize = 11;
+const CHUNK_SIZE: usize = 64;
+const READ_PREFIX: &str = "Successful read of ";
+const READ_TTY_SEPARATOR: &str = " from tty ";
+const READ_PATTERN_SEPARATOR: &str = ", which matches pattern ";
+const UNKNOWN_PREFIX: &str = "Unable to determine response. Response string is: [";
+
+#[derive(Clone,Debug)]
+pub struct ReplayChunk{
+    pub offset: Duration,
+    pub data: Vec<u8>,
+}
+
+//A captured transcript, ready to be fed back through a TTY
+#[derive(Clone,Debug)]
+pub struct Capture{
+    pub ports: Vec<String>,
+    pub chunks: Vec<ReplayChunk>,
+}
+
+impl Capture{
+    //Loads a capture from a transcript or a seymour_life log file. If a log contains more
+    //than one port, `port_filter` selects which one to replay. Files that are neither are
+    //replayed as raw text, one line at a time.
+    pub fn load(path:&str, port_filter:Option<&str>) -> Result<Self,String>{
+        let contents = fs::read(path).map_err(|error| format!("Unable to read {}: {}",path,error))?;
+        let text = String::from_utf8_lossy(&contents);
+        if let Some(capture) = Self::from_transcript(&text,path){
+            return Ok(capture);
+        }
+        if let Some(capture) = Self::from_log(&text,port_filter)?{
+            return Ok(capture);
+        }
+        return Ok(Self::from_raw(&text));
+    }
+
+    fn from_transcript(text:&str, path:&str) -> Option<Self>{
+        let mut chunks = Vec::new();
+        let mut start:Option<DateTime<FixedOffset>> = None;
+        let mut transcript_lines = 0;
+        for line in text.lines(){
+            let mut sections = line.splitn(3,' ');
+            let (Some(timestamp), Some(marker), Some(quoted)) = (sections.next(),sections.next(),sections.next()) else { continue };
+            let Ok(timestamp) = DateTime::parse_from_rfc3339(timestamp) else { continue };
+            if !quoted.starts_with('"') { continue; }
+            transcript_lines += 1;
+            //Only data from the device is replayed; what we wrote to it is implied by the responses
+            if marker != READ_MARKER { continue; }
+            let first = *start.get_or_insert(timestamp);
+            let offset = (timestamp - first).to_std().unwrap_or_default();
+            chunks.push(ReplayChunk{ offset, data: unescape(quoted) });
+        }
+        if transcript_lines == 0 { return None; }
+        let name = path.rsplit('/').next().unwrap_or(path).to_string();
+        return Some(Self{ ports: vec![name], chunks });
+    }
+
+    fn from_log(text:&str, port_filter:Option<&str>) -> Result<Option<Self>,String>{
+        let mut reads:Vec<(DateTime<FixedOffset>,Vec<u8>,Option<String>)> = Vec::new();
+        for line in text.lines(){
+            let mut sections = line.splitn(3," - ");
+            let (Some(timestamp), Some(_level), Some(message)) = (sections.next(),sections.next(),sections.next()) else { continue };
+            let Ok(timestamp) = DateTime::parse_from_rfc3339(timestamp) else { continue };
+            if let Some(read) = message.strip_prefix(READ_PREFIX){
+                let Some((quoted, remainder)) = read.rsplit_once(READ_TTY_SEPARATOR) else { continue };
+                let port = remainder.split(READ_PATTERN_SEPARATOR).next().unwrap_or(remainder);
+                reads.push((timestamp,unescape(quoted),Some(port.to_string())));
+            }
+            else if let Some(read) = message.strip_prefix(UNKNOWN_PREFIX){
+                let quoted = read.strip_suffix(']').unwrap_or(read);
+                reads.push((timestamp,unescape(quoted),None));
+            }
+        }
+        if reads.is_empty() { return Ok(None); }
+
+        let mut ports:Vec<String> = Vec::new();
+        for (_,_,port) in reads.iter(){
+            if let Some(port) = port{
+                if !ports.contains(port) { ports.push(port.clone()); }
+            }
+        }
+        let selected:Vec<String> = match port_filter{
+            Some(filter) => ports.iter().filter(|port| port.contains(filter)).cloned().collect(),
+            None => ports.clone(),
+        };
+        if selected.len() > 1{
+            return Err(format!("Log contains {} ports, select one with --port:\n{}",selected.len(),selected.join("\n")));
+        }
+        //Unclassified reads don't name their port, so they can only be trusted in single-port logs
+        let keep_unnamed = ports.len() <= 1;
+        let mut chunks = Vec::new();
+        let mut start:Option<DateTime<FixedOffset>> = None;
+        for (timestamp,data,port) in reads{
+            let wanted = match port{
+                Some(port) => selected.contains(&port),
+                None => keep_unnamed,
+            };
+            if !wanted { continue; }
+            let first = *start.get_or_insert(timestamp);
+            let offset = (timestamp - first).to_std().unwrap_or_default();
+            split_into_chunks(&mut chunks,offset,&data);
+        }
+        return Ok(Some(Self{ ports: selected, chunks }));
+    }
+
+    fn from_raw(text:&str) -> Self{
+        let mut chunks = Vec::new();
+        let mut offset = Duration::ZERO;
+        for line in text.split_inclusive('\n'){
+            split_into_chunks(&mut chunks,offset,line.as_bytes());
+            offset += Duration::from_millis(50);
+        }
+        return Self{ ports: Vec::new(), chunks };
+    }
+}
+
+//A recorded blob arrives over the wire a few dozen bytes at a time, not all at once
+fn split_into_chunks(chunks:&mut Vec<ReplayChunk>, offset:Duration, data:&[u8]){
+    let mut chunk_offset = offset;
+    for piece in data.chunks(CHUNK_SIZE){
+        chunks.push(ReplayChunk{ offset: chunk_offset, data: piece.to_vec() });
+        chunk_offset += Duration::from_millis((piece.len() / BYTES_PER_MILLISECOND) as u64);
+    }
+}
+
+//Reverses the escaping done by the {:?} formatter on a string, or by ascii::escape_default
+//on raw bytes
+fn unescape(quoted:&str) -> Vec<u8>{
+    let inner = quoted.strip_prefix('"').unwrap_or(quoted);
+    let inner = inner.strip_suffix('"').unwrap_or(inner);
+    let mut output = Vec::new();
+    let mut characters = inner.chars();
+    while let Some(character) = characters.next(){
+        if character != '\\'{
+            let mut encoded = [0u8;4];
+            output.extend_from_slice(character.encode_utf8(&mut encoded).as_bytes());
+            continue;
+        }
+        match characters.next(){
+            Some('n') => output.push(b'\n'),
+            Some('r') => output.push(b'\r'),
+            Some('t') => output.push(b'\t'),
+            Some('0') => output.push(0),
+            Some('x') => {
+                let code:String = characters.by_ref().take(2).collect();
+                if let Ok(byte) = u8::from_str_radix(&code,16){
+                    output.push(byte);
+                }
+            },
+            Some('u') => {
+                let code:String = characters.by_ref().skip(1).take_while(|character| *character != '}').collect();
+                if let Some(decoded) = u32::from_str_radix(&code,16).ok().and_then(char::from_u32){
+                    let mut encoded = [0u8;4];
+                    output.extend_from_slice(decoded.encode_utf8(&mut encoded).as_bytes());
+                }
+            },
+            Some(other) => {
+                let mut encoded = [0u8;4];
+                output.extend_from_slice(other.encode_utf8(&mut encoded).as_bytes());
+            },
+            None => output.push(b'\\'),
+        }
+    }
+    return output;
+}
+
+//Plays a capture back with its original timing. Anything written to it is discarded.
+pub struct ReplayTransport{
+    name: String,
+    chunks: Vec<ReplayChunk>,
+    next: usize,
+    speed: f64,
+    max_gap: Duration,
+    partial: bool,
+    start: Option<Instant>,
+    elapsed: Duration,
+    finished: Arc<AtomicBool>,
+}
+
+impl ReplayTransport{
+    //`speed` scales playback, and gaps longer than `max_gap` are shortened to it
+    pub fn new(name:&str, capture:Capture, speed:f64, max_gap:Duration) -> Self{
+        Self{
+            name: name.to_string(),
+            chunks: capture.chunks,
+            next: 0,
+            speed: if speed > 0.0 { speed } else { 1.0 },
+            max_gap,
+            partial: false,
+            start: None,
+            elapsed: Duration::ZERO,
+            finished: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    //Set once every chunk of the capture has been read
+    pub fn finished(&self) -> Arc<AtomicBool>{
+        return self.finished.clone();
+    }
+}
+
+impl Transport for ReplayTransport{
+    fn read(&mut self, buffer:&mut [u8], timeout:Duration) -> io::Result<usize>{
+        if self.next >= self.chunks.len(){
+            self.finished.store(true,Ordering::SeqCst);
+            thread::sleep(timeout);
+            return Err(io::Error::new(ErrorKind::TimedOut,"Replay finished"));
+        }
+        if !self.partial{
+            let start = *self.start.get_or_insert(Instant::now());
+            let previous = if self.next == 0 { Duration::ZERO } else { self.chunks[self.next - 1].offset };
+            let gap = self.chunks[self.next].offset.saturating_sub(previous).min(self.max_gap);
+            let due = start + (self.elapsed + gap).div_f64(self.speed);
+            let now = Instant::now();
+            if due > now + timeout{
+                thread::sleep(timeout);
+                return Err(io::Error::new(ErrorKind::TimedOut,"Operation timed out"));
+            }
+            thread::sleep(due.saturating_duration_since(now));
+            self.elapsed += gap;
+        }
+        let chunk = &mut self.chunks[self.next];
+        let count = buffer.len().min(chunk.data.len());
+        buffer[..count].copy_from_slice(&chunk.data[..count]);
+        chunk.data.drain(..count);
+        self.partial = !chunk.data.is_empty();
+        if !self.partial{
+            self.next += 1;
+        }
+        return Ok(count);
+    }
+
+    fn write(&mut self, data:&[u8]) -> io::Result<()>{
+        log::trace!("Replay {} discarding write {:?}",self.name,String::from_utf8_lossy(data));
+        return Ok(());
+    }
+
+    fn name(&self) -> Option<String>{
+        return Some(self.name.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::transcript::{Direction, Transcript};
+
+    const UBOOT_LOG: &str = concat!(env!("CARGO_MANIFEST_DIR"),"/log-containing-uboot-prompt.txt");
+
+    fn replayed(capture:&Capture) -> Vec<u8>{
+        return capture.chunks.iter().flat_map(|chunk| chunk.data.clone()).collect();
+    }
+
+    #[test]
+    fn unescape_reverses_debug_formatting(){
+        let original = "\0\nNormal Boot\r\nHit any key:  0 \u{8}\u{8}\t\"quoted\" \\ é\u{7f}";
+        assert_eq!(unescape(&format!("{:?}",original)),original.as_bytes());
+    }
+
+    #[test]
+    fn log_with_several_ports_needs_one_picked(){
+        let error = Capture::load(UBOOT_LOG,None).unwrap_err();
+        assert!(error.contains("select one with --port"),"{}",error);
+        let capture = Capture::load(UBOOT_LOG,Some("1.2.3.3:")).unwrap();
+        assert_eq!(capture.ports.len(),1);
+        let text = String::from_utf8_lossy(&replayed(&capture)).to_string();
+        assert!(text.contains("Hit any key to stop autoboot:  0 \u{8}\u{8}\u{8} 0\r\nu-boot=> "));
+    }
+
+    #[test]
+    fn transcript_reads_back_byte_for_byte(){
+        let directory = std::env::temp_dir().join(format!("seymour_replay_{}",std::process::id()));
+        _ = fs::remove_dir_all(&directory);
+        let read:Vec<u8> = (0..=255).collect();
+        let mut transcript = Transcript::new(&directory.to_string_lossy(),"unit",0,0).unwrap();
+        transcript.record(Direction::Read,&read[..100]);
+        transcript.record(Direction::Written,b"q\n");
+        transcript.record(Direction::Read,&read[100..]);
+        drop(transcript);
+
+        let path = directory.join("unit.transcript").to_string_lossy().to_string();
+        let capture = Capture::load(&path,None).unwrap();
+        assert_eq!(replayed(&capture),read);
+        _ = fs::remove_dir_all(&directory);
+    }
+}
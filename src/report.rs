@@ -0,0 +1,324 @@
+use std::{collections::BTreeSet, fs, io, path::Path, time::Duration};
+use chrono::{DateTime, Local};
+use crate::device::UNINITIALISED_SERIAL;
+use crate::error::Error;
+use crate::journal::{Event, Journal, Totals, JOURNAL_EXTENSION};
+use crate::relay_map::RELAY_MAP_FILE_NAME;
+use crate::results::{results_path, write_atomic, DeviceResults, Targets, LEGACY_EXTENSION, RESULTS_EXTENSION};
+use crate::timing::TimingStats;
+
+pub const REPORT_NAME: &str = "report";
+const HEADERS: [&str; 20] = [
+    "Serial", "Reboots", "BPs", "Temps", "Missed pulls", "Pull fault", "First run", "Last run", "Cycles/hour",
+    "Reboot failures", "BP failures", "Mean BP (s)", "Navigation failures", "U-Boot prompts", "Mean reboot (s)",
+    "Mean boot to menu (s)", "P95 boot to menu (s)", "Target", "Behind target", "Journal mismatch",
+];
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+//One unit's totals, plus what could be worked out from its journal
+#[derive(Clone,PartialEq,Debug)]
+pub struct UnitSummary{
+    pub serial: String,
+    pub reboots: u64,
+    pub bps: u64,
+    pub temps: u64,
+    pub missed_pulls: u64,
+    pub pull_fault: bool,
+    pub first_run: DateTime<Local>,
+    pub last_run: DateTime<Local>,
+    pub target: Targets,
+    pub reboot_failures: u64,
+    pub bp_failures: u64,
+    pub navigation_failures: u64,
+    pub uboot_prompts: u64,
+    pub mean_reboot: Option<Duration>,
+    //From the shutdown command to the debug menu loading
+    pub boot_to_menu: Option<TimingStats>,
+    //Completed BPs only
+    pub bp_duration: Option<TimingStats>,
+    //What the journal adds up to, if the unit has one
+    pub journal_totals: Option<Totals>,
+}
+
+impl UnitSummary{
+    pub fn new(results:&DeviceResults) -> Self{
+        Self{
+            serial: results.serial.clone(),
+            reboots: results.reboots,
+            bps: results.bps,
+            temps: results.temps,
+            missed_pulls: results.pull_stats.missed,
+            pull_fault: results.pull_stats.fault,
+            first_run: results.first_run,
+            last_run: results.last_run,
+            target: results.target,
+            reboot_failures: 0,
+            bp_failures: 0,
+            navigation_failures: 0,
+            uboot_prompts: results.anomalies.uboot_prompts,
+            mean_reboot: None,
+            boot_to_menu: results.boot_times.app_framework,
+            bp_duration: results.bp_stats.duration,
+            journal_totals: None,
+        }
+    }
+
+    //Failure counts and reboot times only exist in the journal, so are left empty without one
+    pub fn add_journal(&mut self, folder:&str){
+        if !Path::new(&results_path(folder,&self.serial,JOURNAL_EXTENSION)).exists(){
+            return;
+        }
+        let records = match Journal::read(folder,&self.serial){
+            Ok(records) => records,
+            Err(error) => {
+                log::warn!("Unable to read journal for {}: {}",self.serial,error);
+                return;
+            }
+        };
+        let mut reboot_times = Vec::new();
+        let mut totals = Totals::default();
+        for record in records{
+            totals.add(&record.event);
+            match record.event{
+                Event::RebootFinished{..} => reboot_times.extend(record.duration_ms),
+                Event::RebootFailed{..} => self.reboot_failures += 1,
+                Event::BpFinished{counted:false,..} => self.bp_failures += 1,
+                Event::NavigationFailed{..} => self.navigation_failures += 1,
+                _ => {},
+            }
+        }
+        if !reboot_times.is_empty(){
+            let total:u64 = reboot_times.iter().sum();
+            self.mean_reboot = Some(Duration::from_millis(total / reboot_times.len() as u64));
+        }
+        self.journal_totals = Some(totals);
+        if self.journal_mismatch(){
+            log::warn!("Journal for {} adds up to {:?}, but its results have {} reboots, {} BPs and {} temps",
+                       self.serial,totals,self.reboots,self.bps,self.temps);
+        }
+    }
+
+    //Whether the journal disagrees with the saved totals, such as after a run cut off part way
+    //through a cycle, or a results file restored from its backup
+    pub fn journal_mismatch(&self) -> bool{
+        return self.journal_totals.is_some_and(|totals| (totals.reboots,totals.bps,totals.temps) != (self.reboots,self.bps,self.temps));
+    }
+
+    //Reboots per hour between the first and last run. None until the unit has run for a while.
+    pub fn cycles_per_hour(&self) -> Option<f64>{
+        let hours = (self.last_run - self.first_run).num_seconds() as f64 / 3600.0;
+        if hours <= 0.0 { return None; }
+        return Some(self.reboots as f64 / hours);
+    }
+
+    //The unit's own target, unless one has been given for every unit
+    pub fn target(&self, targets:&Targets) -> Targets{
+        if targets.is_set() { return *targets; }
+        return self.target;
+    }
+
+    //None if there is nothing to compare against
+    pub fn behind_target(&self, targets:&Targets) -> Option<bool>{
+        let target = self.target(targets);
+        if !target.is_set() { return None; }
+        return Some(!target.reached(self.reboots,self.bps));
+    }
+}
+
+#[derive(Clone,PartialEq,Debug)]
+pub struct Report{
+    pub generated: DateTime<Local>,
+    pub targets: Targets,
+    pub units: Vec<UnitSummary>,
+}
+
+impl Report{
+    //Reads every unit's results from `folder`, including units only tested by older versions.
+    //Nothing in `folder` is changed. A results file that can't be read is skipped with a
+    //warning, rather than leaving every other unit out of the report.
+    pub fn collect(folder:&str, targets:Targets) -> Result<Self,Error>{
+        let entries = fs::read_dir(folder).map_err(|source| Error::Persistence{ path: folder.to_string(), source })?;
+        let mut serials = BTreeSet::new();
+        for entry in entries.flatten(){
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name == RELAY_MAP_FILE_NAME { continue; }
+            let serial = [RESULTS_EXTENSION,LEGACY_EXTENSION].iter()
+                .find_map(|extension| file_name.strip_suffix(&format!(".{}",extension)));
+            let Some(serial) = serial else { continue; };
+            if serial == UNINITIALISED_SERIAL { continue; }
+            serials.insert(serial.to_string());
+        }
+        let mut units = Vec::new();
+        for serial in serials{
+            match DeviceResults::load_read_only(folder,&serial){
+                Ok(Some(results)) => {
+                    let mut unit = UnitSummary::new(&results);
+                    unit.add_journal(folder);
+                    units.push(unit);
+                },
+                Ok(None) => {},
+                Err(error) => log::warn!("Leaving {} out of the report: {}",serial,error),
+            }
+        }
+        return Ok(Self{ generated: Local::now(), targets, units });
+    }
+
+    pub fn behind_target(&self) -> Vec<&UnitSummary>{
+        return self.units.iter().filter(|unit| unit.behind_target(&self.targets) == Some(true)).collect();
+    }
+
+    fn rows(&self) -> Vec<Vec<String>>{
+        return self.units.iter().map(|unit| vec![
+            unit.serial.clone(),
+            unit.reboots.to_string(),
+            unit.bps.to_string(),
+            unit.temps.to_string(),
+            unit.missed_pulls.to_string(),
+            if unit.pull_fault { "yes".to_string() } else { String::new() },
+            unit.first_run.format(TIME_FORMAT).to_string(),
+            unit.last_run.format(TIME_FORMAT).to_string(),
+            unit.cycles_per_hour().map(|rate| format!("{:.2}",rate)).unwrap_or_default(),
+            unit.reboot_failures.to_string(),
+            unit.bp_failures.to_string(),
+            unit.bp_duration.map(|stats| seconds(stats.mean_ms)).unwrap_or_default(),
+            unit.navigation_failures.to_string(),
+            unit.uboot_prompts.to_string(),
+            unit.mean_reboot.map(|mean| format!("{:.1}",mean.as_secs_f64())).unwrap_or_default(),
+            unit.boot_to_menu.map(|stats| seconds(stats.mean_ms)).unwrap_or_default(),
+            unit.boot_to_menu.map(|stats| seconds(stats.p95_ms)).unwrap_or_default(),
+            match unit.target(&self.targets){
+                target if target.is_set() => target.to_string(),
+                _ => String::new(),
+            },
+            match unit.behind_target(&self.targets){
+                Some(true) => "yes".to_string(),
+                Some(false) => "no".to_string(),
+                None => String::new(),
+            },
+            if unit.journal_mismatch() { "yes".to_string() } else { String::new() },
+        ]).collect();
+    }
+
+    fn target_summary(&self) -> String{
+        if self.targets.is_set() { return self.targets.to_string(); }
+        return "as stored for each unit".to_string();
+    }
+
+    pub fn to_csv(&self) -> String{
+        let mut output = String::new();
+        for row in std::iter::once(HEADERS.iter().map(|header| header.to_string()).collect()).chain(self.rows()){
+            let cells:Vec<String> = row.iter().map(|cell| csv_escape(cell)).collect();
+            output += &cells.join(",");
+            output.push('\n');
+        }
+        return output;
+    }
+
+    pub fn to_markdown(&self) -> String{
+        let mut output = format!("# Seymour Life Report\n\nGenerated {}. Units: {}. Targets: {}.\n\n",
+                                 self.generated.format(TIME_FORMAT),self.units.len(),self.target_summary());
+        output += &format!("| {} |\n",HEADERS.join(" | "));
+        output += &format!("|{}\n","---|".repeat(HEADERS.len()));
+        for row in self.rows(){
+            let cells:Vec<String> = row.iter().map(|cell| cell.replace('|',"\\|")).collect();
+            output += &format!("| {} |\n",cells.join(" | "));
+        }
+        let behind = self.behind_target();
+        if !behind.is_empty(){
+            output += "\n## Behind Target\n\n";
+            for unit in behind{
+                output += &format!("- {}: {} reboots, {} BPs\n",unit.serial,unit.reboots,unit.bps);
+            }
+        }
+        return output;
+    }
+
+    //A single page with no external stylesheets or scripts, so it can be mailed or archived as is
+    pub fn to_html(&self) -> String{
+        let mut output = String::from(concat!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Seymour Life Report</title>\n",
+            "<style>\n",
+            "body { font-family: sans-serif; margin: 2em; }\n",
+            "table { border-collapse: collapse; }\n",
+            "th, td { border: 1px solid #999; padding: 0.3em 0.6em; text-align: right; }\n",
+            "th { background: #eee; }\n",
+            "td:first-child, th:first-child { text-align: left; }\n",
+            "tr.behind { background: #fdd; }\n",
+            "</style>\n</head>\n<body>\n<h1>Seymour Life Report</h1>\n"));
+        output += &format!("<p>Generated {}. Units: {}. Targets: {}.</p>\n",
+                           self.generated.format(TIME_FORMAT),self.units.len(),html_escape(&self.target_summary()));
+        output += "<table>\n<tr>";
+        for header in HEADERS{
+            output += &format!("<th>{}</th>",html_escape(header));
+        }
+        output += "</tr>\n";
+        for (unit,row) in self.units.iter().zip(self.rows()){
+            if unit.behind_target(&self.targets) == Some(true){
+                output += "<tr class=\"behind\">";
+            }
+            else {
+                output += "<tr>";
+            }
+            for cell in row{
+                output += &format!("<td>{}</td>",html_escape(&cell));
+            }
+            output += "</tr>\n";
+        }
+        output += "</table>\n</body>\n</html>\n";
+        return output;
+    }
+
+    //Writes report.csv, report.md and report.html into `folder`, returning their paths
+    pub fn write(&self, folder:&str) -> Result<Vec<String>,Error>{
+        fs::create_dir_all(folder).map_err(|source| Error::Persistence{ path: folder.to_string(), source })?;
+        let mut paths = Vec::new();
+        for (extension,contents) in [("csv",self.to_csv()),("md",self.to_markdown()),("html",self.to_html())]{
+            let path = results_path(folder,REPORT_NAME,extension);
+            write_atomic(&path,contents.as_bytes(),false).map_err(|source:io::Error| Error::Persistence{ path: path.clone(), source })?;
+            paths.push(path);
+        }
+        return Ok(paths);
+    }
+}
+
+fn seconds(milliseconds:u64) -> String{
+    return format!("{:.1}",milliseconds as f64 / 1000.0);
+}
+
+fn csv_escape(cell:&str) -> String{
+    if cell.contains([',','"','\n']){
+        return format!("\"{}\"",cell.replace('"',"\"\""));
+    }
+    return cell.to_string();
+}
+
+fn html_escape(text:&str) -> String{
+    return text.replace('&',"&amp;").replace('<',"&lt;").replace('>',"&gt;").replace('"',"&quot;");
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn legacy_units_are_reported_without_touching_the_folder(){
+        let folder = std::env::temp_dir().join(format!("seymour_report_{}",std::process::id()));
+        _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let folder = folder.to_string_lossy().to_string();
+        fs::write(results_path(&folder,"OLD",LEGACY_EXTENSION),"Reboots: 12\nSuccessful BP tests: 30\nSuccessful temp tests: 29\n").unwrap();
+        let mut current = DeviceResults::new("NEW");
+        current.reboots = 4;
+        current.save(&folder).unwrap();
+        let before:BTreeSet<_> = fs::read_dir(&folder).unwrap().flatten().map(|entry| entry.file_name()).collect();
+
+        let report = Report::collect(&folder,Targets::default()).unwrap();
+
+        let after:BTreeSet<_> = fs::read_dir(&folder).unwrap().flatten().map(|entry| entry.file_name()).collect();
+        assert_eq!(before,after);
+        let serials:Vec<&str> = report.units.iter().map(|unit| unit.serial.as_str()).collect();
+        assert_eq!(serials,vec!["NEW","OLD"]);
+        assert_eq!((report.units[1].reboots,report.units[1].bps,report.units[1].temps),(12,30,29));
+        _ = fs::remove_dir_all(&folder);
+    }
+}
@@ -0,0 +1,373 @@
+use std::{fmt, fs::{self, File}, io::{self, Write}, path::Path, process, sync::atomic::{AtomicU64, Ordering}};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use crate::error::Error;
+use crate::journal::BpOutcome;
+use crate::timing::{BootTimes, TimingStats};
+
+//Bump this whenever a field changes meaning, and teach `migrate` how to upgrade older files
+pub const RESULTS_VERSION: u32 = 1;
+pub const RESULTS_EXTENSION: &str = "json";
+pub const LEGACY_EXTENSION: &str = "txt";
+const MIGRATED_EXTENSION: &str = "txt.migrated";
+const BACKUP_SUFFIX: &str = ".bak";
+const TEMP_SUFFIX: &str = ".tmp";
+//Keeps temporary file names unique when several threads write the same file at once
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+const LEGACY_REBOOTS_SECTION: &str = "Reboots";
+const LEGACY_BP_SECTION: &str = "Successful BP tests";
+const LEGACY_TEMP_SECTION: &str = "Successful temp tests";
+const LEGACY_SECTION_SEPARATOR: &str = ": ";
+
+//Where a unit was tested from
+#[derive(Clone,Default,PartialEq,Debug,Serialize,Deserialize)]
+pub struct RigInfo{
+    pub hostname: String,
+    pub port: String,
+    pub relay_pin: Option<u8>,
+}
+
+impl RigInfo{
+    pub fn new(port:&str, relay_pin:Option<u8>) -> Self{
+        Self{ hostname: hostname(), port: port.to_string(), relay_pin }
+    }
+}
+
+//Totals a unit is to be tested up to. Either may be left unset, and a unit with neither set
+//has no target.
+#[derive(Clone,Copy,Default,PartialEq,Debug,Serialize,Deserialize)]
+pub struct Targets{
+    pub reboots: Option<u64>,
+    pub bps: Option<u64>,
+}
+
+impl Targets{
+    pub fn is_set(&self) -> bool{
+        return self.reboots.is_some() || self.bps.is_some();
+    }
+
+    //Whether every part of the target that is set has been reached
+    pub fn reached(&self, reboots:u64, bps:u64) -> bool{
+        return self.is_set()
+            && self.reboots.is_none_or(|target| reboots >= target)
+            && self.bps.is_none_or(|target| bps >= target);
+    }
+}
+
+impl fmt::Display for Targets{
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result{
+        let mut parts = Vec::new();
+        if let Some(reboots) = self.reboots { parts.push(format!("{} reboots",reboots)); }
+        if let Some(bps) = self.bps { parts.push(format!("{} BPs",bps)); }
+        if parts.is_empty() { return write!(f,"none"); }
+        return write!(f,"{}",parts.join(", "));
+    }
+}
+
+//Things a unit did that it shouldn't have, which the test worked around
+#[derive(Clone,Copy,Default,PartialEq,Debug,Serialize,Deserialize)]
+pub struct Anomalies{
+    //Times the unit was found sat at a U-Boot prompt and had to be booted
+    pub uboot_prompts: u64,
+}
+
+//Whether each probe pull made the unit's pull counter go up
+#[derive(Clone,Copy,Default,PartialEq,Debug,Serialize,Deserialize)]
+pub struct PullStats{
+    pub registered: u64,
+    pub missed: u64,
+    //Missed since the last pull that registered
+    pub consecutive_missed: u64,
+    //Set once too many pulls in a row have been missed, and cleared by the next one that registers
+    pub fault: bool,
+}
+
+//How every BP with a known outcome ended, and how long the completed ones took
+#[derive(Clone,Copy,Default,PartialEq,Debug,Serialize,Deserialize)]
+pub struct BpStats{
+    pub completed: u64,
+    pub never_started: u64,
+    pub timed_out: u64,
+    pub aborted_by_reboot: u64,
+    pub duration: Option<TimingStats>,
+}
+
+impl BpStats{
+    pub fn add(&mut self, outcome:BpOutcome){
+        match outcome{
+            BpOutcome::Completed => self.completed += 1,
+            BpOutcome::NeverStarted => self.never_started += 1,
+            BpOutcome::TimedOut => self.timed_out += 1,
+            BpOutcome::AbortedByReboot => self.aborted_by_reboot += 1,
+        }
+    }
+}
+
+//Running totals for a single unit, kept in output/<serial>.json
+#[derive(Clone,PartialEq,Debug,Serialize,Deserialize)]
+pub struct DeviceResults{
+    pub version: u32,
+    pub serial: String,
+    pub reboots: u64,
+    pub bps: u64,
+    pub temps: u64,
+    pub first_run: DateTime<Local>,
+    pub last_run: DateTime<Local>,
+    pub tool_version: String,
+    pub rig: RigInfo,
+    #[serde(default)]
+    pub target: Targets,
+    #[serde(default)]
+    pub anomalies: Anomalies,
+    #[serde(default)]
+    pub boot_times: BootTimes,
+    #[serde(default)]
+    pub bp_stats: BpStats,
+    #[serde(default)]
+    pub pull_stats: PullStats,
+}
+
+impl DeviceResults{
+    pub fn new(serial:&str) -> Self{
+        let now = Local::now();
+        Self{
+            version: RESULTS_VERSION,
+            serial: serial.to_string(),
+            reboots: 0,
+            bps: 0,
+            temps: 0,
+            first_run: now,
+            last_run: now,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            rig: RigInfo::default(),
+            target: Targets::default(),
+            anomalies: Anomalies::default(),
+            boot_times: BootTimes::default(),
+            bp_stats: BpStats::default(),
+            pull_stats: PullStats::default(),
+        }
+    }
+
+    //Loads a unit's results from `folder`. A legacy text file is converted and saved in the
+    //new format the first time it is seen. Returns None if the unit has never been tested.
+    pub fn load(folder:&str, serial:&str) -> Result<Option<Self>,Error>{
+        let Some((results,legacy)) = Self::find(folder,serial)? else { return Ok(None); };
+        if legacy{
+            let legacy_path = results_path(folder,serial,LEGACY_EXTENSION);
+            log::info!("Migrating {} to {}",legacy_path,results_path(folder,serial,RESULTS_EXTENSION));
+            results.save(folder)?;
+            let migrated_path = results_path(folder,serial,MIGRATED_EXTENSION);
+            if let Err(error) = fs::rename(&legacy_path,&migrated_path){
+                log::warn!("Unable to move {} out of the way: {}",legacy_path,error);
+            }
+        }
+        return Ok(Some(results));
+    }
+
+    //As `load`, but a legacy text file is only read, never converted, so `folder` is left as
+    //it was
+    pub fn load_read_only(folder:&str, serial:&str) -> Result<Option<Self>,Error>{
+        return Ok(Self::find(folder,serial)?.map(|(results,_)| results));
+    }
+
+    //Reads the results file, its backup, or the legacy text file, in that order. The flag is
+    //set if the results came from the legacy file.
+    fn find(folder:&str, serial:&str) -> Result<Option<(Self,bool)>,Error>{
+        let path = results_path(folder,serial,RESULTS_EXTENSION);
+        let backup_path = path.clone() + BACKUP_SUFFIX;
+        match Self::read(&path){
+            Ok(Some(results)) => return Ok(Some((results.migrate(),false))),
+            Ok(None) => {},
+            Err(error) => log::warn!("Results for {} are unreadable, trying the backup: {}",serial,error),
+        }
+        //The primary is only ever missing or damaged if a save was interrupted
+        match Self::read(&backup_path){
+            Ok(Some(results)) => {
+                log::warn!("Recovered results for {} from {}",serial,backup_path);
+                return Ok(Some((results.migrate(),false)));
+            },
+            Ok(None) => {
+                if Path::new(&path).exists(){
+                    return Err(Error::Persistence{ path, source: io::Error::new(io::ErrorKind::InvalidData,"results are unreadable and there is no backup") });
+                }
+            },
+            Err(error) => return Err(error),
+        }
+        let legacy_path = results_path(folder,serial,LEGACY_EXTENSION);
+        if !Path::new(&legacy_path).exists(){
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(&legacy_path)
+            .map_err(|error| Error::Persistence{ path: legacy_path.clone(), source: error })?;
+        return Ok(Some((Self::from_legacy(serial,&contents),true)));
+    }
+
+    //Saves through a temporary file, so a power cut leaves either the old results or the new
+    //ones on disk. The previous copy is kept as a backup for `load` to fall back on.
+    pub fn save(&self, folder:&str) -> Result<(),Error>{
+        let path = results_path(folder,&self.serial,RESULTS_EXTENSION);
+        let persistence_error = |source:io::Error| Error::Persistence{ path: path.clone(), source };
+        fs::create_dir_all(folder).map_err(persistence_error)?;
+        let contents = serde_json::to_string_pretty(self).map_err(|error| persistence_error(error.into()))?;
+        write_atomic(&path,contents.as_bytes(),true).map_err(persistence_error)?;
+        return Ok(());
+    }
+
+    //Ok(None) if the file doesn't exist
+    fn read(path:&str) -> Result<Option<Self>,Error>{
+        match fs::read_to_string(path){
+            Ok(contents) => {
+                let results = serde_json::from_str::<Self>(&contents)
+                    .map_err(|error| Error::Persistence{ path: path.to_string(), source: error.into() })?;
+                return Ok(Some(results));
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(Error::Persistence{ path: path.to_string(), source: error }),
+        }
+    }
+
+    //Older versions of the file are upgraded here as the format changes
+    fn migrate(mut self) -> Self{
+        if self.version > RESULTS_VERSION{
+            log::warn!("Results for {} were written by a newer version ({}), some fields may be lost",self.serial,self.version);
+        }
+        self.version = RESULTS_VERSION;
+        return self;
+    }
+
+    //Reads the old `Section: value` text format. Lines that can't be understood are skipped,
+    //rather than taking the whole file down with them.
+    fn from_legacy(serial:&str, contents:&str) -> Self{
+        let mut results = Self::new(serial);
+        for line in contents.lines(){
+            if line.trim().is_empty() { continue; }
+            let Some((section,value)) = line.split_once(LEGACY_SECTION_SEPARATOR) else {
+                log::warn!("Skipping malformed line [{:?}] in legacy results for {}",line,serial);
+                continue;
+            };
+            let Ok(value) = value.trim().parse::<u64>() else {
+                log::warn!("Unable to parse value [{:?}] into integer",line);
+                continue;
+            };
+            match section{
+                LEGACY_REBOOTS_SECTION => results.reboots = value,
+                LEGACY_BP_SECTION => results.bps = value,
+                LEGACY_TEMP_SECTION => results.temps = value,
+                _ => log::warn!("Invalid import value: [{:?}]. Please ensure that the output directory is clean.",line),
+            }
+        }
+        return results;
+    }
+}
+
+pub fn results_path(folder:&str, serial:&str, extension:&str) -> String{
+    return Path::new(folder).join(format!("{}.{}",serial,extension)).to_string_lossy().to_string();
+}
+
+//Writes `contents` to a temporary file next to `path`, flushes it to disk, then renames it over
+//`path`. With `keep_backup`, the file being replaced is first moved to `<path>.bak`.
+pub fn write_atomic(path:&str, contents:&[u8], keep_backup:bool) -> io::Result<()>{
+    let temp_path = format!("{}.{}.{}{}",path,process::id(),TEMP_COUNTER.fetch_add(1,Ordering::SeqCst),TEMP_SUFFIX);
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(contents)?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+    if keep_backup{
+        //Another writer may have just moved the file out of the way itself
+        match fs::rename(path,path.to_string() + BACKUP_SUFFIX){
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {},
+        }
+    }
+    fs::rename(&temp_path,path)?;
+    //The renames only survive a power cut once the directory itself is on disk
+    let folder = Path::new(path).parent().filter(|folder| !folder.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(folder)?.sync_all()?;
+    return Ok(());
+}
+
+fn hostname() -> String{
+    return fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or("unknown".to_string());
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::thread;
+
+    fn test_folder(name:&str) -> String{
+        let folder = std::env::temp_dir().join(format!("seymour_results_{}_{}",name,process::id()));
+        _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        return folder.to_string_lossy().to_string();
+    }
+
+    #[test]
+    fn concurrent_saves_of_one_file_all_succeed(){
+        let folder = test_folder("concurrent");
+        let threads:Vec<_> = (0..8).map(|count| {
+            let folder = folder.clone();
+            thread::spawn(move ||{
+                let mut results = DeviceResults::new("unit");
+                results.reboots = count;
+                return results.save(&folder).is_ok();
+            })
+        }).collect();
+        assert!(threads.into_iter().all(|thread| thread.join().unwrap()));
+        assert!(DeviceResults::load(&folder,"unit").unwrap().is_some());
+        let leftovers = fs::read_dir(&folder).unwrap().flatten().filter(|entry| entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX)).count();
+        assert_eq!(leftovers,0);
+        _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn legacy_results_are_migrated_once(){
+        let folder = test_folder("legacy");
+        fs::write(results_path(&folder,"unit",LEGACY_EXTENSION),"Reboots: 12\nSuccessful BP tests: 30\nSuccessful temp tests: 29\n").unwrap();
+        let results = DeviceResults::load(&folder,"unit").unwrap().unwrap();
+        assert_eq!((results.reboots,results.bps,results.temps),(12,30,29));
+        assert!(!Path::new(&results_path(&folder,"unit",LEGACY_EXTENSION)).exists());
+        assert!(Path::new(&results_path(&folder,"unit",MIGRATED_EXTENSION)).exists());
+        assert_eq!(DeviceResults::read(&results_path(&folder,"unit",RESULTS_EXTENSION)).unwrap(),Some(results));
+        _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn truncated_legacy_lines_are_skipped(){
+        let results = DeviceResults::from_legacy("unit","Reboots: 12\nSuccessful BP tests: 3");
+        assert_eq!((results.reboots,results.bps,results.temps),(12,3,0));
+        let results = DeviceResults::from_legacy("unit","Reboots: 12\nSuccessful BP te");
+        assert_eq!((results.reboots,results.bps),(12,0));
+    }
+
+    #[test]
+    fn truncated_results_fall_back_to_the_backup(){
+        let folder = test_folder("backup");
+        let mut results = DeviceResults::new("unit");
+        results.reboots = 1;
+        results.save(&folder).unwrap();
+        results.reboots = 2;
+        results.save(&folder).unwrap();
+        let path = results_path(&folder,"unit",RESULTS_EXTENSION);
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path,&contents[..contents.len() / 2]).unwrap();
+        assert_eq!(DeviceResults::load(&folder,"unit").unwrap().unwrap().reboots,1);
+        fs::remove_file(path.clone() + BACKUP_SUFFIX).unwrap();
+        assert!(matches!(DeviceResults::load(&folder,"unit"),Err(Error::Persistence{..})));
+        _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn targets_are_reached_once_every_set_part_is(){
+        assert!(!Targets::default().reached(100,100));
+        let reboots = Targets{ reboots: Some(10), bps: None };
+        assert!(!reboots.reached(9,100));
+        assert!(reboots.reached(10,0));
+        let both = Targets{ reboots: Some(10), bps: Some(30) };
+        assert!(!both.reached(10,29));
+        assert!(!both.reached(9,30));
+        assert!(both.reached(11,30));
+    }
+}
@@ -0,0 +1,22 @@
+use std::{process, sync::atomic::{AtomicBool, Ordering}};
+
+//Exit code when a second signal stops the run without waiting, as a shell would for Ctrl-C
+pub const EXIT_FORCED: i32 = 130;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+//On SIGINT or SIGTERM, asks every device to stop after its current step. A second signal exits
+//straight away, leaving relays and counts wherever they were.
+pub fn install_handler() -> Result<(),ctrlc::Error>{
+    return ctrlc::set_handler(||{
+        if REQUESTED.swap(true,Ordering::SeqCst){
+            log::warn!("Stopping immediately, relays may be left energised");
+            process::exit(EXIT_FORCED);
+        }
+        log::warn!("Stopping once every device finishes its current step. Press Ctrl-C again to stop immediately.");
+    });
+}
+
+pub fn requested() -> bool{
+    return REQUESTED.load(Ordering::SeqCst);
+}
@@ -0,0 +1,509 @@
+use std::{collections::HashMap,
+          fs,
+          io::ErrorKind,
+          sync::{Arc, atomic::{AtomicU64, Ordering}},
+          thread,
+          time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use chrono::Local;
+use crate::transport::Transport;
+
+const HOSTNAME: &str = "imx8mplus-seymour-mel";
+const MENU_PROMPT: &str = "\r\n> ";
+const UBOOT_PROMPT: &str = "u-boot=> ";
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const APP_FRAMEWORK_BANNER: &str = "Loading App-Framework from: libapp-framework.so.0.01.00\r\n";
+const DEBUG_MENU: &str = "\r\n\r\nDebug Menu\r\n========================================\r\n\
+This menu is defined in debugmenu.xml. Add submenu tags to this file to add new\r\n\
+categories for your debug programs and scripts.\r\n----------------------------------------\r\n\
+a) Menu Demo\r\nb) Alarms Manager Menu\r\nB) Barcode Manager Menu\r\nc) Connectivity Manager Menu\r\n\
+C) SCRM Menu\r\nd) Software Update Menu\r\nD) Pulse Rate Menu\r\ne) Events Menu\r\nE) Respiration Menu\r\n\
+f) Configuration Application Menu\r\nh) HostProxy Menu\r\nH) HL7Proxy Menu\r\nl) LDAP/Active Directory Menu\r\n\
+L) Life Cycle Testing Menu\r\nm) ModManager Menu\r\nM) Manual Set Parameters Menu\r\nn) NIBP Menu\r\n\
+P) Power Manager Menu\r\np) PDM Menu\r\nr) Radio Menu\r\nR) Braun Menu\r\ns) SpO2 Menu\r\nS) SvcMonProxy Menu\r\n\
+T) NTPProxy Menu\r\nu) UI Menu\r\nU) SureTemp Menu\r\nv) Connector Test\r\nx) Proxy Events Menu\r\n\
+y) SysCtrl Menu\r\nw) Weight App Manager Menu\r\nW) BEST menu\r\nz) Print Manager Debug Menu\r\nZ) LCD Test Menu\r\n\
+v) Debug Menu Verbosity\r\n----------------------------------------\r\n\\) Back\r\nq) Quit\r\n?) Redraw menu";
+const LIFECYCLE_MENU: &str = "\r\n\r\nLife Cycle Testing Menu\r\n========================================\r\n\
+a) EEPROM Data\r\nB) Screen Brightness Menu\r\nb) Power Down Device\r\nc) Reboot Device\r\nd) Check UI Started\r\n\
+e) Read Battery Charge Level\r\nf) Nurse Call On\r\ng) Nurse Call Off\r\nh) Display Current Readings\r\n\
+H) Display Cycle Counts\r\ni) Force Log Rotation\r\nj) Display Patient ID\r\nk) Activate Wireless Networking\r\n\
+l) Deactivate Wireless Networking\r\nN) Start NIBP Reading\r\nn) Check NIBP In Progress\r\np) Bluetooth Info\r\n\
+r) WiFi Radio Info\r\ns) Enable and Start SSH\r\nt) Disable and Stop SSH\r\nu) Check WACP USB Connection\r\n\
+----------------------------------------\r\n\\) Back\r\nq) Quit\r\n?) Redraw menu";
+const BRIGHTNESS_MENU: &str = "\r\n\r\nSet Screen Brightness\r\n****************************************\r\n\
+1) 10%\r\n2) 20%\r\n3) 30%\r\n4) 40%\r\n5) 50%\r\n6) 60%\r\n7) 70%\r\n8) 80%\r\n9) 90%\r\n0) 100%\r\n\
+----------------------------------------\r\n\\) Back\r\nq) Quit\r\n?) Redraw menu";
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum SimState{
+    UBoot,
+    LoginPrompt,
+    PasswordPrompt,
+    ShellPrompt,
+    DebugMenu,
+    LifecycleMenu,
+    BrightnessMenu,
+    Hung,
+}
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Fault{
+    //Send the response to a command, but not the prompt that should follow it
+    DropPrompt,
+    //Send random bytes ahead of the response to a command
+    Garbage,
+    //Wait before responding to a command
+    Stall(Duration),
+    //Stop responding entirely
+    Hang,
+    //Stop at the U-Boot prompt on the next reboot
+    UBoot,
+    //Never finish the next NIBP reading
+    BPStuck,
+}
+
+impl Fault{
+    fn parse(name:&str, argument:Option<&str>) -> Result<Self,String>{
+        match name{
+            "drop-prompt" => Ok(Fault::DropPrompt),
+            "garbage" => Ok(Fault::Garbage),
+            "hang" => Ok(Fault::Hang),
+            "uboot" => Ok(Fault::UBoot),
+            "bp-stuck" => Ok(Fault::BPStuck),
+            "stall" => {
+                let seconds = argument.unwrap_or("10").parse::<f64>()
+                    .map_err(|_| format!("Invalid stall duration: {:?}",argument))?;
+                Ok(Fault::Stall(Duration::from_secs_f64(seconds)))
+            },
+            _ => Err(format!("Unknown fault: {}",name)),
+        }
+    }
+}
+
+//Faults to inject at a given command number. One entry per line, in the form
+//`<command number> <fault> [seconds]`, where fault is one of drop-prompt, garbage,
+//stall, hang, uboot or bp-stuck. Lines starting with # are ignored.
+#[derive(Clone,Default,Debug)]
+pub struct FaultScript{
+    entries: HashMap<u64,Vec<Fault>>,
+}
+
+impl FaultScript{
+    pub fn load(path:&str) -> Result<Self,String>{
+        let contents = fs::read_to_string(path).map_err(|error| format!("Unable to read fault script {}: {}",path,error))?;
+        return Self::parse(&contents);
+    }
+
+    pub fn parse(contents:&str) -> Result<Self,String>{
+        let mut output = Self::default();
+        for (line_number,line) in contents.lines().enumerate(){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let mut words = line.split_whitespace();
+            let command_number = words.next().unwrap_or("").parse::<u64>()
+                .map_err(|_| format!("Line {}: invalid command number",line_number+1))?;
+            let fault_name = words.next().ok_or(format!("Line {}: missing fault",line_number+1))?;
+            let fault = Fault::parse(fault_name,words.next()).map_err(|error| format!("Line {}: {}",line_number+1,error))?;
+            output.entries.entry(command_number).or_default().push(fault);
+        }
+        return Ok(output);
+    }
+
+    fn take(&mut self, command_number:u64) -> Vec<Fault>{
+        return self.entries.remove(&command_number).unwrap_or_default();
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct SimulatorConfig{
+    pub serial: String,
+    pub initial_state: SimState,
+    pub boot_time: Duration,
+    pub bp_duration: Duration,
+    pub drop_prompt_rate: f64,
+    pub garbage_rate: f64,
+    pub stall_rate: f64,
+    pub stall_duration: Duration,
+    pub uboot_rate: f64,
+    pub seed: u64,
+    pub script: FaultScript,
+}
+
+impl Default for SimulatorConfig{
+    fn default() -> Self{
+        Self{
+            serial: "SIM00001".to_string(),
+            initial_state: SimState::LoginPrompt,
+            boot_time: Duration::from_secs(5),
+            bp_duration: Duration::from_secs(10),
+            drop_prompt_rate: 0.0,
+            garbage_rate: 0.0,
+            stall_rate: 0.0,
+            stall_duration: Duration::from_secs(10),
+            uboot_rate: 0.0,
+            seed: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(1),
+            script: FaultScript::default(),
+        }
+    }
+}
+
+//Emulates a single Seymour unit on the far end of a transport: login prompt, root shell,
+//the python debug menu, NIBP readings, the SureTemp pull counter and the reboot sequence.
+pub struct Simulator{
+    transport: Box<dyn Transport>,
+    config: SimulatorConfig,
+    state: SimState,
+    line: String,
+    outbox: Vec<u8>,
+    commands: u64,
+    rng: u64,
+    temp_pulls: Arc<AtomicU64>,
+    bp_pulls: u64,
+    bp_end: Option<Instant>,
+    bp_stuck: bool,
+    uboot_next_boot: bool,
+    drop_prompt: bool,
+}
+
+impl Simulator{
+    pub fn new(transport:Box<dyn Transport>, config:SimulatorConfig) -> Self{
+        let rng = config.seed.max(1);
+        let state = config.initial_state;
+        Self{
+            transport,
+            config,
+            state,
+            line: String::new(),
+            outbox: Vec::new(),
+            commands: 0,
+            rng,
+            temp_pulls: Arc::new(AtomicU64::new(0)),
+            bp_pulls: 0,
+            bp_end: None,
+            bp_stuck: false,
+            uboot_next_boot: false,
+            drop_prompt: false,
+        }
+    }
+
+    //Shared handle to the SureTemp probe pull counter, so a relay can register pulls.
+    pub fn temp_counter(&self) -> Arc<AtomicU64>{
+        return self.temp_pulls.clone();
+    }
+
+    pub fn run(mut self){
+        let mut buffer = [0u8;256];
+        loop{
+            match self.transport.read(&mut buffer,POLL_INTERVAL){
+                Ok(0) => {
+                    log::debug!("Simulated unit {} lost its connection",self.config.serial);
+                    return;
+                },
+                Ok(count) => {
+                    for byte in buffer[..count].iter().copied(){
+                        self.handle_byte(byte);
+                    }
+                    self.flush();
+                },
+                Err(error) if error.kind() == ErrorKind::TimedOut => {},
+                Err(error) => {
+                    log::error!("Simulated unit {} failed to read: {}",self.config.serial,error);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn next_random(&mut self) -> u64{
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        return self.rng;
+    }
+
+    fn chance(&mut self, rate:f64) -> bool{
+        if rate <= 0.0 { return false; }
+        return (self.next_random() % 1_000_000) as f64 / 1_000_000.0 < rate;
+    }
+
+    fn send(&mut self, text:&str){
+        self.outbox.extend_from_slice(text.as_bytes());
+    }
+
+    fn prompt(&mut self, prompt:&str){
+        if self.drop_prompt{
+            log::debug!("Simulated unit {} dropping prompt {:?}",self.config.serial,prompt);
+            return;
+        }
+        self.send(prompt);
+    }
+
+    fn flush(&mut self){
+        if self.outbox.is_empty() { return; }
+        let data = std::mem::take(&mut self.outbox);
+        if let Err(error) = self.transport.write(&data){
+            log::warn!("Simulated unit {} failed to write: {}",self.config.serial,error);
+        }
+    }
+
+    //Throw away anything sent while the unit was not listening
+    fn drain_input(&mut self){
+        let mut buffer = [0u8;256];
+        while let Ok(count) = self.transport.read(&mut buffer,Duration::from_millis(1)){
+            if count == 0 { break; }
+        }
+        self.line.clear();
+    }
+
+    fn start_command(&mut self){
+        self.commands += 1;
+        self.drop_prompt = false;
+        let mut faults = self.config.script.take(self.commands);
+        if self.chance(self.config.drop_prompt_rate) { faults.push(Fault::DropPrompt); }
+        if self.chance(self.config.garbage_rate) { faults.push(Fault::Garbage); }
+        if self.chance(self.config.stall_rate) { faults.push(Fault::Stall(self.config.stall_duration)); }
+        if self.chance(self.config.uboot_rate) { faults.push(Fault::UBoot); }
+        for fault in faults{
+            log::info!("Simulated unit {} injecting {:?} at command {}",self.config.serial,fault,self.commands);
+            match fault{
+                Fault::DropPrompt => self.drop_prompt = true,
+                Fault::Garbage => {
+                    let length = 8 + (self.next_random() % 56) as usize;
+                    for _ in 0..length{
+                        let byte = (self.next_random() & 0xff) as u8;
+                        self.outbox.push(byte);
+                    }
+                },
+                Fault::Stall(duration) => {
+                    self.flush();
+                    thread::sleep(duration);
+                },
+                Fault::Hang => self.state = SimState::Hung,
+                Fault::UBoot => self.uboot_next_boot = true,
+                Fault::BPStuck => self.bp_stuck = true,
+            }
+        }
+    }
+
+    fn handle_byte(&mut self, byte:u8){
+        match self.state{
+            SimState::Hung => {},
+            SimState::UBoot | SimState::LoginPrompt | SimState::PasswordPrompt | SimState::ShellPrompt => {
+                match byte{
+                    b'\r' => {},
+                    b'\n' => {
+                        self.send("\r\n");
+                        let line = std::mem::take(&mut self.line);
+                        self.start_command();
+                        if self.state != SimState::Hung{
+                            self.handle_line(line.trim());
+                        }
+                    },
+                    _ => {
+                        if self.state != SimState::PasswordPrompt{
+                            self.outbox.push(byte);
+                        }
+                        self.line.push(byte as char);
+                    }
+                }
+            },
+            SimState::DebugMenu | SimState::LifecycleMenu | SimState::BrightnessMenu => {
+                if byte == b'\r' { return; }
+                self.start_command();
+                if self.state != SimState::Hung{
+                    self.handle_key(byte as char);
+                }
+            },
+        }
+    }
+
+    fn handle_line(&mut self, line:&str){
+        match self.state{
+            SimState::LoginPrompt => {
+                if line == "root"{
+                    let last_login = Local::now().format("%a %b %e %H:%M:%S UTC %Y").to_string();
+                    self.send(&format!("Last login: {}\r\n",last_login));
+                    self.shell_prompt();
+                }
+                else if line.is_empty(){
+                    self.login_prompt();
+                }
+                else{
+                    self.state = SimState::PasswordPrompt;
+                    self.prompt("Password: ");
+                }
+            },
+            SimState::PasswordPrompt => {
+                self.send("\r\nLogin incorrect\r\n");
+                self.login_prompt();
+            },
+            SimState::ShellPrompt => {
+                match line{
+                    "" => self.shell_prompt(),
+                    "python3 -m debugmenu" => {
+                        self.send(APP_FRAMEWORK_BANNER);
+                        self.state = SimState::DebugMenu;
+                        self.menu(DEBUG_MENU);
+                    },
+                    "echo 'y1q' | python3 -m debugmenu" => {
+                        self.send(APP_FRAMEWORK_BANNER);
+                        self.send(DEBUG_MENU);
+                        self.send(MENU_PROMPT);
+                        let serial = self.config.serial.clone();
+                        self.send(&format!("\r\nDtCtrlCfgDeviceModelNum: \"SEYMOUR\"\r\nDtCtrlCfgDeviceSerialNum: \"{}\"\r\n",serial));
+                        self.send(MENU_PROMPT);
+                        self.send("\r\nEXIT Debug menu\r\n");
+                        self.shell_prompt();
+                    },
+                    "shutdown -r now" => self.reboot(),
+                    "exit" | "logout" => self.login_prompt(),
+                    _ => {
+                        let command = line.split_whitespace().next().unwrap_or("").to_string();
+                        self.send(&format!("-sh: {}: command not found\r\n",command));
+                        self.shell_prompt();
+                    }
+                }
+            },
+            SimState::UBoot => {
+                match line{
+                    "" => self.prompt(UBOOT_PROMPT),
+                    "boot" => self.boot_kernel(),
+                    _ => {
+                        self.send(&format!("Unknown command '{}' - try 'help'\r\n",line));
+                        self.prompt(UBOOT_PROMPT);
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn handle_key(&mut self, key:char){
+        match (self.state, key){
+            (_, '\n') => self.prompt(MENU_PROMPT),
+            (_, 'q') => self.exit_menu(),
+            (_, '?') => self.redraw(),
+            (SimState::DebugMenu, '\\') => self.exit_menu(),
+            (SimState::DebugMenu, 'L') => {
+                self.state = SimState::LifecycleMenu;
+                self.menu(LIFECYCLE_MENU);
+            },
+            (SimState::LifecycleMenu, '\\') => {
+                self.state = SimState::DebugMenu;
+                self.menu(DEBUG_MENU);
+            },
+            (SimState::LifecycleMenu, 'B') => {
+                self.state = SimState::BrightnessMenu;
+                self.menu(BRIGHTNESS_MENU);
+            },
+            (SimState::LifecycleMenu, 'N') => {
+                if !self.is_bp_running(){
+                    self.bp_end = Some(Instant::now() + self.config.bp_duration);
+                    self.bp_pulls += 1;
+                }
+                self.send("\r\nStarting NIBP reading");
+                self.prompt(MENU_PROMPT);
+            },
+            (SimState::LifecycleMenu, 'n') => {
+                let text = if self.is_bp_running() { "True" } else { "False" };
+                self.send(&format!("\r\nCheck NIBP In Progress: {}",text));
+                self.prompt(MENU_PROMPT);
+            },
+            (SimState::LifecycleMenu, 'H') => {
+                let pulls = self.temp_pulls.load(Ordering::SeqCst);
+                self.send(&format!("\r\nNIBP Cycles: {}\r\nSureTemp Probe Pulls: {}",self.bp_pulls,pulls));
+                self.prompt(MENU_PROMPT);
+            },
+            (SimState::LifecycleMenu, 'c') => {
+                self.send("\r\nEXIT Debug menu\r\n");
+                self.reboot();
+            },
+            (SimState::BrightnessMenu, '\\') => {
+                self.state = SimState::LifecycleMenu;
+                self.menu(LIFECYCLE_MENU);
+            },
+            (SimState::BrightnessMenu, '0'..='9') => self.prompt(MENU_PROMPT),
+            _ => self.prompt(MENU_PROMPT),
+        }
+    }
+
+    fn is_bp_running(&mut self) -> bool{
+        match self.bp_end{
+            Some(end) => {
+                if self.bp_stuck || Instant::now() < end { return true; }
+                self.bp_end = None;
+                return false;
+            },
+            None => return false,
+        }
+    }
+
+    fn menu(&mut self, text:&str){
+        self.send(text);
+        self.prompt(MENU_PROMPT);
+    }
+
+    fn redraw(&mut self){
+        match self.state{
+            SimState::DebugMenu => self.menu(DEBUG_MENU),
+            SimState::LifecycleMenu => self.menu(LIFECYCLE_MENU),
+            SimState::BrightnessMenu => self.menu(BRIGHTNESS_MENU),
+            _ => {},
+        }
+    }
+
+    fn exit_menu(&mut self){
+        self.send("\r\nEXIT Debug menu\r\n");
+        self.shell_prompt();
+    }
+
+    fn shell_prompt(&mut self){
+        self.state = SimState::ShellPrompt;
+        self.prompt(&format!("root@{}:~# ",HOSTNAME));
+    }
+
+    fn login_prompt(&mut self){
+        self.state = SimState::LoginPrompt;
+        self.prompt(&format!("\r\r\n{} login: ",HOSTNAME));
+    }
+
+    fn reboot(&mut self){
+        //An in-progress NIBP reading is abandoned by a reboot, but a stuck pump stays stuck
+        self.bp_end = None;
+        self.send(&format!("\r\nBroadcast message from root@{} (ttymxc2):\r\n\r\nThe system is going down for reboot NOW!\r\n",HOSTNAME));
+        self.flush();
+        thread::sleep(self.config.boot_time / 5);
+        self.send("reboot: Restarting system\r\n");
+        self.flush();
+        thread::sleep(self.config.boot_time / 5);
+        self.send("\r\nU-Boot SPL 2020.04\r\nNormal Boot\r\nHit any key to stop autoboot:  0 \r\n");
+        if self.uboot_next_boot{
+            self.uboot_next_boot = false;
+            self.drain_input();
+            self.state = SimState::UBoot;
+            self.prompt(UBOOT_PROMPT);
+            return;
+        }
+        self.boot_kernel();
+    }
+
+    fn boot_kernel(&mut self){
+        self.send("Starting kernel ...\r\n\r\n");
+        self.flush();
+        thread::sleep(self.config.boot_time * 3 / 5);
+        self.drain_input();
+        self.send(&format!("\r\r\n{} ttymxc2\r\n\r\nHillrom Front Line Care Embedded Linux for mel 12 {} ttymxc2\r\n",HOSTNAME,HOSTNAME));
+        self.login_prompt();
+    }
+}
+
+//Counts up the trailing digits of a serial, keeping their width
+pub fn nth_serial(first:&str, index:u32) -> String{
+    let digits = first.chars().rev().take_while(|character| character.is_ascii_digit()).count();
+    let (prefix, number) = first.split_at(first.len() - digits);
+    match number.parse::<u64>(){
+        Ok(value) => format!("{}{:0width$}",prefix,value + index as u64,width = digits),
+        Err(_) if index == 0 => first.to_string(),
+        Err(_) => format!("{}{}",first,index),
+    }
+}
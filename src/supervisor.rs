@@ -0,0 +1,158 @@
+use std::{any::Any,
+          sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+          thread::{self, JoinHandle},
+          time::{Duration, Instant}};
+
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(600);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+//Recovery can wait out a reboot and a boot from U-Boot in one step, so it is given longer
+pub const DEFAULT_RECOVERY_TIMEOUT: Duration = Duration::from_secs(1200);
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum UnitStatus{
+    Running,
+    //No progress for too long. The unit's thread has been interrupted and should recover it.
+    Stalled,
+    Recovering,
+    //Recovery failed, or the thread never came back to try. Left out of the rest of the run.
+    Quarantined,
+}
+
+#[derive(Debug)]
+struct HeartbeatState{
+    last_beat: Instant,
+    status: UnitStatus,
+}
+
+//Shared between a unit's thread, which beats it whenever the test moves along, and the
+//supervisor watching for units that have stopped moving
+#[derive(Clone,Debug)]
+pub struct Heartbeat{
+    state: Arc<Mutex<HeartbeatState>>,
+    interrupt: Arc<AtomicBool>,
+}
+
+impl Default for Heartbeat{
+    fn default() -> Self{
+        Self{
+            state: Arc::new(Mutex::new(HeartbeatState{ last_beat: Instant::now(), status: UnitStatus::Running })),
+            interrupt: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Heartbeat{
+    pub fn new() -> Self{
+        return Self::default();
+    }
+
+    pub fn beat(&self){
+        self.lock().last_beat = Instant::now();
+    }
+
+    pub fn status(&self) -> UnitStatus{
+        return self.lock().status;
+    }
+
+    //Recovering or running again lifts the interrupt, so the unit can be talked to again
+    pub fn set_status(&self, status:UnitStatus){
+        let mut state = self.lock();
+        state.status = status;
+        state.last_beat = Instant::now();
+        self.interrupt.store(matches!(status,UnitStatus::Stalled | UnitStatus::Quarantined),Ordering::SeqCst);
+    }
+
+    //Set while the unit is stalled or quarantined. The unit's TTY stops reading once this is set.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool>{
+        return self.interrupt.clone();
+    }
+
+    fn since_last_beat(&self) -> Duration{
+        return self.lock().last_beat.elapsed();
+    }
+
+    //A thread that panicked while holding the lock can't leave the state half-written, so the
+    //poisoning is ignored
+    fn lock(&self) -> std::sync::MutexGuard<'_,HeartbeatState>{
+        return self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+}
+
+struct Unit{
+    serial: String,
+    heartbeat: Heartbeat,
+    thread: Option<JoinHandle<bool>>,
+}
+
+//Watches every unit's thread. A unit that makes no progress for `stall_timeout` is interrupted
+//so its thread can try to recover it. One whose thread doesn't start recovering within another
+//`stall_timeout`, or whose recovery goes `recovery_timeout` without moving on a step, is
+//quarantined and no longer waited on. Its thread stops at its next check of the unit's status.
+pub struct Supervisor{
+    stall_timeout: Duration,
+    recovery_timeout: Duration,
+    units: Vec<Unit>,
+}
+
+impl Supervisor{
+    pub fn new(stall_timeout:Duration, recovery_timeout:Duration) -> Self{
+        Self{ stall_timeout, recovery_timeout, units: Vec::new() }
+    }
+
+    pub fn watch(&mut self, serial:&str, heartbeat:Heartbeat, thread:JoinHandle<bool>){
+        self.units.push(Unit{ serial: serial.to_string(), heartbeat, thread: Some(thread) });
+    }
+
+    //Waits for every unit to finish or be quarantined. Returns whether all of them finished
+    //successfully.
+    pub fn wait(&mut self) -> bool{
+        let mut all_succeeded = true;
+        loop{
+            for unit in self.units.iter_mut(){
+                let Some(ref thread) = unit.thread else { continue; };
+                if thread.is_finished(){
+                    let thread = unit.thread.take().unwrap();
+                    match thread.join(){
+                        Ok(true) => {},
+                        Ok(false) => all_succeeded = false,
+                        Err(panic) => {
+                            log::error!("Thread for device {} panicked: {}",unit.serial,panic_message(&panic));
+                            all_succeeded = false;
+                        }
+                    }
+                    continue;
+                }
+                let status = unit.heartbeat.status();
+                let timeout = if status == UnitStatus::Recovering { self.recovery_timeout } else { self.stall_timeout };
+                if unit.heartbeat.since_last_beat() < timeout { continue; }
+                match status{
+                    UnitStatus::Running => {
+                        log::warn!("Device {} has made no progress for {:?}, interrupting it",unit.serial,timeout);
+                        unit.heartbeat.set_status(UnitStatus::Stalled);
+                    },
+                    UnitStatus::Stalled | UnitStatus::Recovering => {
+                        log::error!("Device {} did not recover within {:?}, quarantining it. Its relay may be left energised.",unit.serial,timeout);
+                        unit.heartbeat.set_status(UnitStatus::Quarantined);
+                        unit.thread = None;
+                        all_succeeded = false;
+                    },
+                    UnitStatus::Quarantined => {},
+                }
+            }
+            if self.units.iter().all(|unit| unit.thread.is_none()){
+                return all_succeeded;
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    }
+}
+
+fn panic_message(panic:&Box<dyn Any + Send>) -> String{
+    if let Some(message) = panic.downcast_ref::<&str>(){
+        return message.to_string();
+    }
+    if let Some(message) = panic.downcast_ref::<String>(){
+        return message.clone();
+    }
+    return "unknown panic".to_string();
+}
@@ -0,0 +1,132 @@
+use std::time::Instant;
+use serde::{Deserialize, Serialize};
+
+//Summary of a set of durations, all in milliseconds
+#[derive(Clone,Copy,Default,PartialEq,Debug,Serialize,Deserialize)]
+pub struct TimingStats{
+    pub count: u64,
+    pub min_ms: u64,
+    pub mean_ms: u64,
+    pub max_ms: u64,
+    pub p95_ms: u64,
+}
+
+impl TimingStats{
+    //None if there is nothing to summarise. The 95th percentile is by nearest rank.
+    pub fn from_samples(samples:&[u64]) -> Option<Self>{
+        if samples.is_empty() { return None; }
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+        let count = sorted.len();
+        let total:u64 = sorted.iter().sum();
+        let p95_rank = (count * 95).div_ceil(100).max(1);
+        return Some(Self{
+            count: count as u64,
+            min_ms: sorted[0],
+            mean_ms: total / count as u64,
+            max_ms: sorted[count - 1],
+            p95_ms: sorted[p95_rank - 1],
+        });
+    }
+}
+
+//How long one reboot took to reach each point, counted from the shutdown command. A point that
+//wasn't seen, such as a kernel restart line lost in the noise, is left empty.
+#[derive(Clone,Copy,Default,PartialEq,Debug,Serialize,Deserialize)]
+pub struct BootTiming{
+    pub kernel_restart_ms: Option<u64>,
+    pub login_prompt_ms: Option<u64>,
+    pub shell_prompt_ms: Option<u64>,
+    pub app_framework_ms: Option<u64>,
+    //The unit was stopped, recovered or finished its run before it reached the debug menu
+    #[serde(default)]
+    pub incomplete: bool,
+}
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum BootPhase{
+    KernelRestart,
+    LoginPrompt,
+    ShellPrompt,
+    AppFramework,
+}
+
+//Times a reboot as the unit passes each phase
+#[derive(Clone,Copy,Debug)]
+pub struct BootClock{
+    started: Instant,
+    timing: BootTiming,
+}
+
+impl BootClock{
+    pub fn start() -> Self{
+        Self{ started: Instant::now(), timing: BootTiming::default() }
+    }
+
+    //Only the first time a phase is reached counts
+    pub fn mark(&mut self, phase:BootPhase){
+        let elapsed = Some(self.started.elapsed().as_millis() as u64);
+        let slot = match phase{
+            BootPhase::KernelRestart => &mut self.timing.kernel_restart_ms,
+            BootPhase::LoginPrompt => &mut self.timing.login_prompt_ms,
+            BootPhase::ShellPrompt => &mut self.timing.shell_prompt_ms,
+            BootPhase::AppFramework => &mut self.timing.app_framework_ms,
+        };
+        if slot.is_none() { *slot = elapsed; }
+    }
+
+    pub fn timing(&self) -> BootTiming{
+        return self.timing;
+    }
+}
+
+//Boot timings over every timed reboot of a unit, kept in its results
+#[derive(Clone,Copy,Default,PartialEq,Debug,Serialize,Deserialize)]
+pub struct BootTimes{
+    pub kernel_restart: Option<TimingStats>,
+    pub login_prompt: Option<TimingStats>,
+    pub shell_prompt: Option<TimingStats>,
+    pub app_framework: Option<TimingStats>,
+}
+
+impl BootTimes{
+    pub fn from_timings(timings:&[BootTiming]) -> Self{
+        let stats = |phase:fn(&BootTiming) -> Option<u64>| {
+            let samples:Vec<u64> = timings.iter().filter_map(phase).collect();
+            return TimingStats::from_samples(&samples);
+        };
+        return Self{
+            kernel_restart: stats(|timing| timing.kernel_restart_ms),
+            login_prompt: stats(|timing| timing.login_prompt_ms),
+            shell_prompt: stats(|timing| timing.shell_prompt_ms),
+            app_framework: stats(|timing| timing.app_framework_ms),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn stats_use_the_nearest_rank_for_p95(){
+        assert_eq!(TimingStats::from_samples(&[]),None);
+        let samples:Vec<u64> = (1..=20).rev().collect();
+        let stats = TimingStats::from_samples(&samples).unwrap();
+        assert_eq!((stats.count,stats.min_ms,stats.mean_ms,stats.max_ms,stats.p95_ms),(20,1,10,20,19));
+        let single = TimingStats::from_samples(&[7]).unwrap();
+        assert_eq!((single.min_ms,single.mean_ms,single.max_ms,single.p95_ms),(7,7,7,7));
+    }
+
+    #[test]
+    fn boot_times_skip_phases_that_were_not_seen(){
+        let timings = [
+            BootTiming{ login_prompt_ms: Some(100), app_framework_ms: Some(300), ..Default::default() },
+            BootTiming{ login_prompt_ms: Some(200), incomplete: true, ..Default::default() },
+        ];
+        let times = BootTimes::from_timings(&timings);
+        assert_eq!(times.kernel_restart,None);
+        assert_eq!(times.login_prompt.map(|stats| (stats.count,stats.mean_ms)),Some((2,150)));
+        assert_eq!(times.app_framework.map(|stats| stats.count),Some(1));
+    }
+}
@@ -0,0 +1,157 @@
+use std::{fs::{self, File, OpenOptions},
+          io::{self, Write},
+          path::{Path, PathBuf}};
+use chrono::Local;
+
+pub const READ_MARKER: &str = "<<";
+pub const WRITE_MARKER: &str = ">>";
+pub const TRANSCRIPT_EXTENSION: &str = "transcript";
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Direction{
+    Read,
+    Written,
+}
+
+//Raw record of every byte passing through a TTY. Each chunk is one line:
+//`<rfc3339 timestamp> <<|>> "<escaped bytes>"`, where << is data read from the device
+//and >> is data written to it. Once the file passes `max_bytes`, it is rotated to
+//`<name>.transcript.1` and so on, keeping at most `keep` old files.
+#[derive(Debug)]
+pub struct Transcript{
+    directory: PathBuf,
+    name: String,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: u32,
+}
+
+impl Transcript{
+    pub fn new(directory:&str, name:&str, max_bytes:u64, keep:u32) -> io::Result<Self>{
+        fs::create_dir_all(directory)?;
+        let directory = PathBuf::from(directory);
+        let name = file_safe_name(name);
+        let path = transcript_path(&directory,&name,0);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        return Ok(Self{ directory, name, file, size, max_bytes, keep });
+    }
+
+    pub fn record(&mut self, direction:Direction, data:&[u8]){
+        let marker = match direction{
+            Direction::Read => READ_MARKER,
+            Direction::Written => WRITE_MARKER,
+        };
+        let escaped:String = data.iter().flat_map(|byte| std::ascii::escape_default(*byte)).map(char::from).collect();
+        let line = format!("{} {} \"{}\"\n",Local::now().to_rfc3339(),marker,escaped);
+        if let Err(error) = self.file.write_all(line.as_bytes()){
+            log::warn!("Unable to write transcript {}: {}",self.name,error);
+            return;
+        }
+        self.size += line.len() as u64;
+        if self.max_bytes > 0 && self.size >= self.max_bytes{
+            if let Err(error) = self.rotate(){
+                log::warn!("Unable to rotate transcript {}: {}",self.name,error);
+            }
+        }
+    }
+
+    //Moves the transcript to a new name, such as a serial number once one is known, along with
+    //any segments already rotated out. Anything recorded under the new name is kept as older
+    //than this transcript, and only the newest `keep` rotated segments are kept overall.
+    pub fn rename(&mut self, name:&str) -> io::Result<()>{
+        let name = file_safe_name(name);
+        if name == self.name { return Ok(()); }
+        let rotated = (1..=self.keep).take_while(|index| transcript_path(&self.directory,&self.name,*index).exists()).count() as u32;
+        let new_path = transcript_path(&self.directory,&name,0);
+        if rotated > 0{
+            //The new name's segments, and its current file, go behind this transcript's segments
+            let active = u32::from(new_path.exists());
+            let existing = (1..).take_while(|index| transcript_path(&self.directory,&name,*index).exists()).count() as u32;
+            for index in (1..=existing).rev(){
+                fs::rename(transcript_path(&self.directory,&name,index),transcript_path(&self.directory,&name,index + rotated + active))?;
+            }
+            if active > 0{
+                fs::rename(&new_path,transcript_path(&self.directory,&name,rotated + 1))?;
+            }
+            for index in 1..=rotated{
+                fs::rename(transcript_path(&self.directory,&self.name,index),transcript_path(&self.directory,&name,index))?;
+            }
+            for index in (self.keep + 1)..=(rotated + active + existing){
+                fs::remove_file(transcript_path(&self.directory,&name,index))?;
+            }
+        }
+        let old_path = transcript_path(&self.directory,&self.name,0);
+        let mut file = OpenOptions::new().create(true).append(true).open(&new_path)?;
+        file.write_all(&fs::read(&old_path)?)?;
+        fs::remove_file(&old_path)?;
+        self.size = file.metadata()?.len();
+        self.file = file;
+        self.name = name;
+        return Ok(());
+    }
+
+    fn rotate(&mut self) -> io::Result<()>{
+        self.file.flush()?;
+        if self.keep == 0{
+            self.file = File::create(transcript_path(&self.directory,&self.name,0))?;
+            self.size = 0;
+            return Ok(());
+        }
+        for index in (1..self.keep).rev(){
+            let older = transcript_path(&self.directory,&self.name,index);
+            if older.exists(){
+                fs::rename(&older,transcript_path(&self.directory,&self.name,index + 1))?;
+            }
+        }
+        let current = transcript_path(&self.directory,&self.name,0);
+        fs::rename(&current,transcript_path(&self.directory,&self.name,1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&current)?;
+        self.size = 0;
+        return Ok(());
+    }
+}
+
+fn transcript_path(directory:&Path, name:&str, index:u32) -> PathBuf{
+    if index == 0{
+        return directory.join(format!("{}.{}",name,TRANSCRIPT_EXTENSION));
+    }
+    return directory.join(format!("{}.{}.{}",name,TRANSCRIPT_EXTENSION,index));
+}
+
+//Port names are full paths; only the last section is useful as a file name
+fn file_safe_name(name:&str) -> String{
+    let last_section = name.rsplit('/').next().unwrap_or(name);
+    return last_section.chars().map(|character| if character.is_alphanumeric() || "-_.:".contains(character) { character } else { '_' }).collect();
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn rename_moves_rotated_segments_ahead_of_older_ones(){
+        let directory = std::env::temp_dir().join(format!("seymour_transcript_{}",std::process::id()));
+        _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        for (index,contents) in [(0,"serial active"),(1,"serial 1")]{
+            fs::write(transcript_path(&directory,"SERIAL",index),contents).unwrap();
+        }
+        for (index,contents) in [(0,"port active"),(1,"port 1"),(2,"port 2")]{
+            fs::write(transcript_path(&directory,"ttyUSB0",index),contents).unwrap();
+        }
+        let mut transcript = Transcript::new(&directory.to_string_lossy(),"/dev/ttyUSB0",0,3).unwrap();
+
+        transcript.rename("SERIAL").unwrap();
+
+        let read = |index| fs::read_to_string(transcript_path(&directory,"SERIAL",index)).ok();
+        assert_eq!(read(0).as_deref(),Some("port active"));
+        assert_eq!(read(1).as_deref(),Some("port 1"));
+        assert_eq!(read(2).as_deref(),Some("port 2"));
+        assert_eq!(read(3).as_deref(),Some("serial active"));
+        assert_eq!(read(4),None);
+        assert!(!transcript_path(&directory,"ttyUSB0",1).exists());
+        _ = fs::remove_dir_all(&directory);
+    }
+}
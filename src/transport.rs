@@ -0,0 +1,104 @@
+use std::{io::{self, Read, Write, ErrorKind},
+          sync::mpsc::{self, Receiver, Sender, RecvTimeoutError},
+          time::Duration};
+use serialport::{SerialPort, TTYPort};
+
+//Anything a TTY can talk to. A read that sees no data within `timeout` returns an
+//ErrorKind::TimedOut error, matching the behaviour of serialport; Ok(0) means the
+//other end is gone.
+pub trait Transport: Send {
+    fn read(&mut self, buffer:&mut [u8], timeout:Duration) -> io::Result<usize>;
+    fn write(&mut self, data:&[u8]) -> io::Result<()>;
+    fn name(&self) -> Option<String>;
+}
+
+pub struct SerialTransport{
+    port: Box<dyn SerialPort>,
+}
+
+impl SerialTransport{
+    pub fn open(serial_location:&str, baud_rate:u32, timeout:Duration) -> serialport::Result<Self>{
+        let port = serialport::new(serial_location,baud_rate).timeout(timeout).open()?;
+        return Ok(Self{ port });
+    }
+
+    //Opens a pseudo-terminal pair. The first transport is the master side, the second is
+    //the slave side, whose name can be handed to anything expecting a real serial port.
+    pub fn pty() -> serialport::Result<(Self,Self)>{
+        let (master, slave) = TTYPort::pair()?;
+        return Ok((Self{ port: Box::new(master) }, Self{ port: Box::new(slave) }));
+    }
+}
+
+impl Transport for SerialTransport{
+    fn read(&mut self, buffer:&mut [u8], timeout:Duration) -> io::Result<usize>{
+        if self.port.timeout() != timeout{
+            self.port.set_timeout(timeout)?;
+        }
+        return self.port.read(buffer);
+    }
+
+    fn write(&mut self, data:&[u8]) -> io::Result<()>{
+        self.port.write_all(data)?;
+        return self.port.flush();
+    }
+
+    fn name(&self) -> Option<String>{
+        return self.port.name();
+    }
+}
+
+//In-memory byte pipe. Anything written to one half of a pair can be read from the other.
+pub struct MemoryTransport{
+    name: String,
+    incoming: Receiver<Vec<u8>>,
+    outgoing: Sender<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl MemoryTransport{
+    pub fn pair(name:&str) -> (Self,Self){
+        let (first_sender, first_receiver) = mpsc::channel();
+        let (second_sender, second_receiver) = mpsc::channel();
+        let first = Self{
+            name: name.to_string(),
+            incoming: first_receiver,
+            outgoing: second_sender,
+            pending: Vec::new(),
+        };
+        let second = Self{
+            name: name.to_string() + "-peer",
+            incoming: second_receiver,
+            outgoing: first_sender,
+            pending: Vec::new(),
+        };
+        return (first, second);
+    }
+}
+
+impl Transport for MemoryTransport{
+    fn read(&mut self, buffer:&mut [u8], timeout:Duration) -> io::Result<usize>{
+        if self.pending.is_empty(){
+            match self.incoming.recv_timeout(timeout){
+                Ok(data) => self.pending = data,
+                Err(RecvTimeoutError::Timeout) => return Err(io::Error::new(ErrorKind::TimedOut,"Operation timed out")),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let count = buffer.len().min(self.pending.len());
+        buffer[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        return Ok(count);
+    }
+
+    fn write(&mut self, data:&[u8]) -> io::Result<()>{
+        if self.outgoing.send(data.to_vec()).is_err(){
+            return Err(io::Error::new(ErrorKind::BrokenPipe,"Memory transport peer dropped"));
+        }
+        return Ok(());
+    }
+
+    fn name(&self) -> Option<String>{
+        return Some(self.name.clone());
+    }
+}
@@ -1,13 +1,22 @@
-use std::{collections::HashMap, 
-          io::{BufReader, Write, Read}, 
+use std::{collections::{HashMap, VecDeque}, 
+          io::ErrorKind,
           boxed::Box,
-          time::Duration};
+          sync::{Arc, atomic::{AtomicBool, Ordering}},
+          time::{Duration, Instant}};
 use once_cell::sync::Lazy;
-use serialport::SerialPort;
 use derivative::Derivative;
+use crate::transport::{Transport, SerialTransport};
+use crate::transcript::{Transcript, Direction};
+use crate::error::Error;
 
 const BAUD_RATE:u32 = 115200;
 const SERIAL_TIMEOUT: std::time::Duration = Duration::from_millis(500);
+const FRAME_POLL: Duration = Duration::from_millis(20);
+const BURST_QUIET: Duration = Duration::from_millis(100);
+const PROMPT_ENDINGS: [&str;3] = [":", "#", ">"];
+//Headers followed by a value on the same line. A line ending in one of these is still waiting
+//for its value, not a prompt.
+const VALUE_HEADERS: [&str;2] = ["Probe Pulls:", "In Progress:"];
 
 
 #[derive(Eq,Derivative,Debug)]
@@ -54,7 +63,7 @@ pub enum Response{
 }
 
 
-const COMMAND_MAP:Lazy<HashMap<Command,&str>> = Lazy::new(||HashMap::from([
+static COMMAND_MAP:Lazy<HashMap<Command,&str>> = Lazy::new(||HashMap::from([
     (Command::Quit, "q\n"),
     (Command::StartBP, "N"),
     (Command::CheckBPState, "n"),
@@ -80,7 +89,7 @@ const COMMAND_RESPONSES: [&str;3] = [
 ];
 
 const RESPONSES:[(&str,Response);14] = [
-    ("uboot=>",Response::UBoot),
+    ("u-boot=>",Response::UBoot),
     ("Last login:",Response::PreShellPrompt),
     ("reboot: Restarting",Response::Rebooting),
     ("command not found",Response::FailedDebugMenu),
@@ -97,8 +106,13 @@ const RESPONSES:[(&str,Response);14] = [
 ];
 
 pub struct TTY{
-    tty: Box<dyn SerialPort>,
+    tty: Box<dyn Transport>,
     last: Command,
+    transcript: Option<Transcript>,
+    pending: Vec<u8>,
+    responses: VecDeque<Response>,
+    password_prompt: bool,
+    interrupt: Option<Arc<AtomicBool>>,
 }
 impl std::fmt::Debug for TTY{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
@@ -121,93 +135,296 @@ impl std::fmt::Debug for TTY{
 }
 
 impl TTY{
-    pub fn new(serial_location:&str) -> Option<Self>{
-        let possible_tty = serialport::new(serial_location,BAUD_RATE).timeout(SERIAL_TIMEOUT).open();
-        if let Ok(tty) = possible_tty{
-            Some(TTY{tty,last:Command::Quit})
-        } else{
-            None
+    pub fn new(serial_location:&str) -> Result<Self,Error>{
+        let tty = SerialTransport::open(serial_location,BAUD_RATE,SERIAL_TIMEOUT)?;
+        return Ok(TTY::from_transport(Box::new(tty)));
+    }
+
+    pub fn from_transport(tty:Box<dyn Transport>) -> Self{
+        TTY{
+            tty,
+            last:Command::Quit,
+            transcript:None,
+            pending:Vec::new(),
+            responses:VecDeque::new(),
+            password_prompt:false,
+            interrupt:None
         }
     }
 
-    pub fn write_to_device(&mut self,command:Command) -> bool {
+    pub fn port_name(&self) -> String{
+        return self.tty.name().unwrap_or("unknown".to_string());
+    }
+
+    //Once `interrupt` is set, reads fail with Error::Stalled until it is cleared again
+    pub fn set_interrupt(&mut self, interrupt:Arc<AtomicBool>){
+        self.interrupt = Some(interrupt);
+    }
+
+    fn interrupted(&self) -> bool{
+        return self.interrupt.as_ref().is_some_and(|interrupt| interrupt.load(Ordering::SeqCst));
+    }
+
+    pub fn start_transcript(&mut self, transcript:Transcript){
+        self.transcript = Some(transcript);
+    }
+
+    pub fn rename_transcript(&mut self, name:&str){
+        if let Some(ref mut transcript) = self.transcript{
+            if let Err(error) = transcript.rename(name){
+                log::warn!("Unable to rename transcript for {:?} to {}: {}",self,name,error);
+            }
+        }
+    }
+
+    pub fn write_to_device(&mut self,command:Command) -> Result<(),Error> {
         if command == self.last{
             log::trace!("retry send {}",self.tty.name().unwrap_or("unknown".to_string()));
         }else{
             log::debug!("writing {:?} to tty {}...", command, self.tty.name().unwrap_or("unknown".to_string()));
         };
-        let output = self.tty.write_all(COMMAND_MAP.get(&command).unwrap().as_bytes()).is_ok();
+        //Anything still queued was a response to an earlier command, and nobody wanted it
+        if !self.responses.is_empty(){
+            log::trace!("Discarding unread responses {:?} from tty {:?}",self.responses,self);
+            self.responses.clear();
+        }
+        let command_string = COMMAND_MAP.get(&command).unwrap().as_bytes();
+        let output = self.tty.write(command_string).map_err(Error::SerialIo);
+        if let Some(ref mut transcript) = self.transcript{
+            transcript.record(Direction::Written,command_string);
+        }
         self.last = command;
-        _ = self.tty.flush();
-        std::thread::sleep(SERIAL_TIMEOUT);
         return output;
     }
 
-    pub fn read_from_device(&mut self,_break_char:Option<&str>) -> Response {
-        let mut reader = BufReader::new(&mut self.tty);
-        let mut read_buffer: Vec<u8> = Vec::new();
-        _ = reader.read_to_end(&mut read_buffer);
-        if read_buffer.len() > 0 {
-            let read_line:String = String::from_utf8_lossy(read_buffer.as_slice()).to_string();
-            if read_line.eq("\r\n") {
-                return Response::EmptyNewline;
-            } 
-            for command in COMMAND_RESPONSES{
-                if read_line.trim().eq(command.trim()){
-                    return self.read_from_device(None);
-                }
-            };
-            for (string,enum_value) in RESPONSES{
-                if read_line.contains(string){
-                    if(enum_value == Response::BPOn) || (enum_value == Response::BPOff) {
-                        //Don't log BPOn or BPOff, we're gonna see a LOT of those and we don't want
-                        //to overfill the SD card
+    //Returns the next response from the device. Output is framed into lines as it arrives,
+    //and every recognised line is queued in order, so several prompts arriving at once or a
+    //prompt split across reads are not lost. A read ends once the device has been quiet for
+    //BURST_QUIET after saying something recognisable, rather than waiting out SERIAL_TIMEOUT.
+    //Returns Empty if nothing arrives within SERIAL_TIMEOUT, and Other if data arrived but
+    //none of it was recognised.
+    pub fn read_from_device(&mut self,_break_char:Option<&str>) -> Result<Response,Error> {
+        if self.interrupted(){
+            return Err(Error::Stalled);
+        }
+        if let Some(response) = self.responses.pop_front(){
+            return Ok(response);
+        }
+        let mut chunk = [0u8;1024];
+        let mut idle = Duration::ZERO;
+        let mut read_anything = false;
+        let mut only_blank_lines = true;
+        loop{
+            if self.interrupted(){
+                return Err(Error::Stalled);
+            }
+            match self.tty.read(&mut chunk,FRAME_POLL){
+                Ok(0) => {
+                    only_blank_lines &= self.frame(true);
+                    break;
+                },
+                Ok(count) => {
+                    if let Some(ref mut transcript) = self.transcript{
+                        transcript.record(Direction::Read,&chunk[..count]);
                     }
-                    else{
-                        log::trace!("Successful read of {:?} from tty {}, which matches pattern {:?}",read_line,self.tty.name().unwrap_or("unknown shell".to_string()),enum_value);
-                    };
-                    if enum_value == Response::TempCount(None){
-                        let mut lines = read_line.lines();
-                        while let Some(single_line) = lines.next(){
-                            if single_line.contains(string){
-                                let trimmed_line = single_line.trim();
-                                match trimmed_line.rsplit_once(' '){
-                                    None =>  return enum_value,
-                                    Some((_header,temp_count)) => {
-                                        match temp_count.trim().parse::<u64>(){
-                                            Err(_) => {
-                                                log::error!("String {} from device {} unable to be parsed!",temp_count,self.tty.name().unwrap_or("unknown shell".to_string()));
-                                                return Response::TempCount(None)
-                                            },
-                                            Ok(parsed_temp_count) => {
-                                                log::trace!("parsed temp count for device {}: {}",self.tty.name().unwrap_or("unknown shell".to_string()),temp_count);
-                                                return Response::TempCount(Some(parsed_temp_count))
-                                            }
-                                        }
-                                    }
+                    self.pending.extend_from_slice(&chunk[..count]);
+                    read_anything = true;
+                    idle = Duration::ZERO;
+                    only_blank_lines &= self.frame(false);
+                },
+                Err(error) if error.kind() == ErrorKind::TimedOut => {
+                    idle += FRAME_POLL;
+                    //A partial line is only taken as complete once the device has been quiet for
+                    //a whole SERIAL_TIMEOUT, as a slow device can pause part way through a line
+                    only_blank_lines &= self.frame(idle >= SERIAL_TIMEOUT);
+                    if !self.responses.is_empty() && idle >= BURST_QUIET { break; }
+                    if idle >= SERIAL_TIMEOUT { break; }
+                },
+                Err(error) => {
+                    log::trace!("Read error on tty {:?}: {}",self,error);
+                    return Err(Error::SerialIo(error));
+                }
+            }
+            if self.password_prompt{
+                self.password_prompt = false;
+                log::error!("Recieved password prompt on device {}! Something fell apart here. Check preceeding log lines.",self.tty.name().unwrap_or("unknown shell".to_string()));
+                self.write_to_device(Command::Newline)?;
+            }
+        }
+        if let Some(response) = self.responses.pop_front(){
+            return Ok(response);
+        }
+        if !read_anything {
+            log::trace!("Read an empty string from device {:?}. Possible read error.", self);
+            return Ok(Response::Empty);
+        }
+        if only_blank_lines {
+            return Ok(Response::EmptyNewline);
+        }
+        return Ok(Response::Other);
+    }
+
+    //Reads until the device sends one of the expected responses, skipping anything else, and
+    //returns it. Responses carrying data match regardless of the data, so TempCount(None)
+    //will match TempCount(Some(12)).
+    pub fn expect(&mut self, expected:&[Response], timeout:Duration) -> Result<Response,Error>{
+        let start = Instant::now();
+        let mut last:Option<Response> = None;
+        loop{
+            let response = self.read_from_device(None)?;
+            if expected.iter().any(|candidate| std::mem::discriminant(candidate) == std::mem::discriminant(&response)){
+                return Ok(response);
+            }
+            if response != Response::Empty{
+                log::trace!("Skipping {:?} from tty {:?} while waiting for {:?}",response,self,expected);
+                last = Some(response);
+            }
+            if start.elapsed() >= timeout{
+                let error = Error::Timeout{ expected: expected.to_vec(), waited: start.elapsed(), last };
+                log::warn!("TTY {:?} {}",self,error);
+                return Err(error);
+            }
+        }
+    }
+
+    //Splits complete lines off the pending buffer and queues a response for each recognised
+    //one. A trailing partial line is only taken if it is a prompt, or if `flush` is set.
+    //Returns whether every line taken was blank.
+    fn frame(&mut self, flush:bool) -> bool{
+        let mut only_blank_lines = true;
+        while let Some(position) = self.pending.iter().position(|byte| *byte == b'\n'){
+            let line:Vec<u8> = self.pending.drain(..=position).collect();
+            only_blank_lines &= self.take_line(&String::from_utf8_lossy(&line));
+        }
+        if !self.pending.is_empty(){
+            let partial = String::from_utf8_lossy(&self.pending).to_string();
+            let is_prompt = PROMPT_ENDINGS.iter().any(|ending| partial.trim_end().ends_with(ending))
+                && !VALUE_HEADERS.iter().any(|header| partial.contains(header));
+            if flush || (is_prompt && Self::classify(&partial).is_some()){
+                self.pending.clear();
+                only_blank_lines &= self.take_line(&partial);
+            }
+        }
+        return only_blank_lines;
+    }
+
+    //Queues the response for one line of output. Returns whether the line was blank.
+    fn take_line(&mut self, read_line:&str) -> bool{
+        let trimmed_line = read_line.trim();
+        if trimmed_line.is_empty() {
+            return true;
+        }
+        for command in COMMAND_RESPONSES{
+            if trimmed_line.eq(command.trim()){
+                return false;
+            }
+        };
+        match Self::classify(read_line){
+            Some((string,enum_value)) => {
+                if(enum_value == Response::BPOn) || (enum_value == Response::BPOff) {
+                    //Don't log BPOn or BPOff, we're gonna see a LOT of those and we don't want
+                    //to overfill the SD card
+                }
+                else{
+                    log::trace!("Successful read of {:?} from tty {}, which matches pattern {:?}",read_line,self.tty.name().unwrap_or("unknown shell".to_string()),enum_value);
+                };
+                if enum_value == Response::TempCount(None){
+                    match trimmed_line.rsplit_once(' '){
+                        None => self.responses.push_back(enum_value),
+                        Some((_header,temp_count)) => {
+                            match temp_count.trim().parse::<u64>(){
+                                Err(_) => {
+                                    log::error!("String {} from device {} unable to be parsed!",temp_count,self.tty.name().unwrap_or("unknown shell".to_string()));
+                                    self.responses.push_back(Response::TempCount(None));
+                                },
+                                Ok(parsed_temp_count) => {
+                                    log::trace!("parsed temp count for device {}: {}",self.tty.name().unwrap_or("unknown shell".to_string()),temp_count);
+                                    self.responses.push_back(Response::TempCount(Some(parsed_temp_count)));
                                 }
                             }
                         }
                     }
-                    else if enum_value == Response::Serial(None) {
-                        return Response::Serial(Some(read_line));
-                    }
-                    else if enum_value == Response::PasswordPrompt {
-                        log::error!("Recieved password prompt on device {}! Something fell apart here. Check preceeding log lines.",self.tty.name().unwrap_or("unknown shell".to_string()));
-                        self.write_to_device(Command::Newline);
-                        _ = self.read_from_device(None);
-                    }
-                    else{
-                        return enum_value;
-                    }
                 }
+                else if enum_value == Response::Serial(None) {
+                    self.responses.push_back(Response::Serial(Some(read_line.to_string())));
+                }
+                else if enum_value == Response::PasswordPrompt {
+                    log::trace!("Password prompt found matching {:?}",string);
+                    self.password_prompt = true;
+                }
+                else{
+                    self.responses.push_back(enum_value);
+                }
+            },
+            None => {
+                log::trace!("Unable to determine response. Response string is: [{:?}]",read_line);
             }
-            log::trace!("Unable to determine response. Response string is: [{:?}]",read_line);
-            return Response::Other;
         }
-        else {
-            log::trace!("Read an empty string from device {:?}. Possible read error.", self);
-            return Response::Empty;
-        };
+        return false;
+    }
+
+    fn classify(read_line:&str) -> Option<(&'static str,Response)>{
+        for (string,enum_value) in RESPONSES{
+            if read_line.contains(string){
+                return Some((string,enum_value));
+            }
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::thread;
+    use crate::transport::MemoryTransport;
+
+    //Sends each chunk to a new TTY, waiting `gap` between them
+    fn tty_receiving(chunks:&[&str], gap:Duration) -> TTY{
+        let (local, mut remote) = MemoryTransport::pair("test");
+        let chunks:Vec<String> = chunks.iter().map(|chunk| chunk.to_string()).collect();
+        thread::spawn(move ||{
+            for chunk in chunks{
+                remote.write(chunk.as_bytes()).unwrap();
+                thread::sleep(gap);
+            }
+            //Keep the pipe open until the reader has given up waiting
+            thread::sleep(SERIAL_TIMEOUT * 4);
+        });
+        return TTY::from_transport(Box::new(local));
+    }
+
+    #[test]
+    fn value_header_split_from_its_value(){
+        let mut tty = tty_receiving(&["\r\nSureTemp Probe Pulls:"," 12\r\n> "],Duration::from_millis(50));
+        assert_eq!(tty.expect(&[Response::TempCount(None)],Duration::from_secs(2)).unwrap(),Response::TempCount(Some(12)));
+    }
+
+    #[test]
+    fn value_split_mid_word(){
+        let mut tty = tty_receiving(&["\r\nCheck NIBP In Progress: Tr","ue\r\n> "],Duration::from_millis(40));
+        assert_eq!(tty.read_from_device(None).unwrap(),Response::BPOn);
+        assert_eq!(tty.read_from_device(None).unwrap(),Response::DebugMenu);
+    }
+
+    #[test]
+    fn prompt_split_across_chunks(){
+        let mut tty = tty_receiving(&["\r\nseymour lo","gin: "],Duration::from_millis(40));
+        assert_eq!(tty.read_from_device(None).unwrap(),Response::LoginPrompt);
+    }
+
+    #[test]
+    fn coalesced_responses_are_all_queued(){
+        let mut tty = tty_receiving(&["\r\nCheck NIBP In Progress: False\r\n> \r\nNIBP Cycles: 3\r\nSureTemp Probe Pulls: 7\r\n> "],Duration::ZERO);
+        assert_eq!(tty.read_from_device(None).unwrap(),Response::BPOff);
+        assert_eq!(tty.read_from_device(None).unwrap(),Response::DebugMenu);
+        assert_eq!(tty.read_from_device(None).unwrap(),Response::TempCount(Some(7)));
+        assert_eq!(tty.read_from_device(None).unwrap(),Response::DebugMenu);
+    }
+
+    #[test]
+    fn unterminated_line_is_taken_after_quiet(){
+        let mut tty = tty_receiving(&["\r\nreboot: Restarting system"],Duration::ZERO);
+        assert_eq!(tty.read_from_device(None).unwrap(),Response::Rebooting);
     }
 }
@@ -0,0 +1,86 @@
+use seymour_life::results::{DeviceResults, Targets};
+use std::{fs, path::PathBuf, process::Command, thread, time::{Duration, Instant}};
+
+const SERIAL: &str = "SIM00001";
+
+fn run_folder(name:&str) -> PathBuf{
+    let folder = std::env::temp_dir().join(format!("seymour_{}_{}",name,std::process::id()));
+    _ = fs::remove_dir_all(&folder);
+    fs::create_dir_all(folder.join("output")).unwrap();
+    return folder;
+}
+
+//An unattended run with no iteration count of its own carries a unit on toward the target
+//saved with its results
+#[test]
+fn config_run_resumes_toward_a_saved_target(){
+    let folder = run_folder("resume");
+    let mut results = DeviceResults::new(SERIAL);
+    results.reboots = 2;
+    results.target = Targets{ reboots: Some(4), bps: None };
+    results.save(&folder.join("output").to_string_lossy()).unwrap();
+    fs::write(folder.join("profiles.toml"),"[default]\nbps_per_reboot = 1\ntemp_pull_seconds = 0.0\n").unwrap();
+    fs::write(folder.join("cfg.toml"),concat!(
+        "profiles = \"profiles.toml\"\n\n",
+        "[logging]\nfolder = \"logs\"\nstdout = false\n\n",
+        "[simulate]\nboot_seconds = 0.2\nbp_seconds = 0.2\nseed = 1\n",
+    )).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_seymour_life"))
+        .args(["--config","cfg.toml","--simulate","1"])
+        .current_dir(&folder)
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(),Some(0));
+    let results = DeviceResults::load(&folder.join("output").to_string_lossy(),SERIAL).unwrap().unwrap();
+    assert!(results.reboots >= 4,"only reached {} reboots",results.reboots);
+    _ = fs::remove_dir_all(&folder);
+}
+
+//Every BP ends as soon as it starts, so none is ever counted and the unit has to be given up on
+#[test]
+fn unit_making_no_bp_progress_is_given_up_on(){
+    let folder = run_folder("no_progress");
+    fs::write(folder.join("profiles.toml"),"[default]\nbps_per_reboot = 1\ntemp_pull_seconds = 0.0\n").unwrap();
+    fs::write(folder.join("cfg.toml"),concat!(
+        "target_bps = 5\nprofiles = \"profiles.toml\"\n\n",
+        "[logging]\nfolder = \"logs\"\nstdout = false\n\n",
+        "[simulate]\nboot_seconds = 0.1\nbp_seconds = 0.0\nseed = 1\n",
+    )).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_seymour_life"))
+        .args(["--config","cfg.toml","--simulate","1"])
+        .current_dir(&folder)
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(120);
+    let status = loop{
+        if let Some(status) = child.try_wait().unwrap() { break status; }
+        if Instant::now() >= deadline{
+            _ = child.kill();
+            panic!("the unit was never given up on");
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    assert_eq!(status.code(),Some(1));
+    let results = DeviceResults::load(&folder.join("output").to_string_lossy(),SERIAL).unwrap().unwrap();
+    assert_eq!(results.bps,0);
+    _ = fs::remove_dir_all(&folder);
+}
+
+#[test]
+fn unusable_log_folder_is_a_config_error(){
+    let folder = run_folder("bad_logs");
+    fs::write(folder.join("cfg.toml"),"iterations = 1\n\n[logging]\nfolder = \"/proc/nope\"\n").unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_seymour_life"))
+        .args(["--config","cfg.toml","--simulate","1"])
+        .current_dir(&folder)
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(),Some(2));
+    _ = fs::remove_dir_all(&folder);
+}